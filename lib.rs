@@ -9,11 +9,65 @@
 #![allow(clippy::should_implement_trait)]
 
 use std::any::{type_name, Any, TypeId};
-use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{btree_map, BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::rc::{Rc, Weak};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
 
-#[derive(Default, Debug)]
+/// Emits a `tracing` event and/or a `log` record for a store operation on type
+/// `T`, if the `tracing` or `log` features are enabled.
+macro_rules! trace {
+    ($op:literal, $t:ty) => {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(r#type = type_name::<$t>(), $op);
+        #[cfg(feature = "log")]
+        log::trace!("{} {}", $op, type_name::<$t>());
+    };
+}
+
+/// A container holding at most one value of any Rust type.
+///
+/// Values are kept in a `BTreeMap` keyed by `TypeId`, so iterating the store
+/// through `Deref` visits them in `TypeId` order.
+///
+/// The type name of each value is captured when it is inserted with a type
+/// parameter, e.g. via [`put`](Self::put). Values inserted in erased form via
+/// [`insert_any`](Self::insert_any) have no captured name.
+#[derive(Default)]
 pub struct GothamStore {
     data: BTreeMap<TypeId, Box<dyn Any>>,
+    meta: BTreeMap<TypeId, Meta>,
+    frozen: BTreeMap<TypeId, &'static str>,
+    #[cfg(feature = "serde")]
+    json: BTreeMap<String, serde_json::Value>,
+    observers: BTreeMap<TypeId, Box<Observer>>,
+    cache: RefCell<BTreeMap<TypeId, Box<dyn Any>>>,
+    missing_formatter: Option<Box<MissingFormatter>>,
+    watchers: Vec<Sender<TypeId>>,
+    sealed: bool,
+    resolving: Vec<(TypeId, &'static str)>,
+    keyed: KeyedStore,
+    defaults: BTreeMap<TypeId, (Box<dyn Any>, CloneValue)>,
+    lenses: BTreeMap<&'static str, Box<Lens>>,
+}
+
+impl std::fmt::Debug for GothamStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("GothamStore");
+        d.field("data", &self.data)
+            .field("meta", &self.meta)
+            .field("frozen", &self.frozen)
+            .field("sealed", &self.sealed)
+            .field("keyed", &self.keyed);
+        #[cfg(feature = "serde")]
+        d.field("json", &self.json);
+        d.finish_non_exhaustive()
+    }
 }
 
 impl std::ops::Deref for GothamStore {
@@ -24,185 +78,4329 @@ impl std::ops::Deref for GothamStore {
     }
 }
 
+impl IntoIterator for GothamStore {
+    type Item = (TypeId, Box<dyn Any>);
+    type IntoIter = std::collections::btree_map::IntoIter<TypeId, Box<dyn Any>>;
+
+    /// Consumes the `GothamStore`, yielding every value in `TypeId` order.
+    fn into_iter(mut self) -> Self::IntoIter {
+        std::mem::take(&mut self.data).into_iter()
+    }
+}
+
+impl Drop for GothamStore {
+    /// Drops the values in ascending order of their drop priority, as set by
+    /// [`put_ordered`](GothamStore::put_ordered). Values with the same priority
+    /// are dropped in `TypeId` order.
+    fn drop(&mut self) {
+        let mut entries: Vec<_> = std::mem::take(&mut self.data)
+            .into_iter()
+            .map(|(id, b)| (self.meta.get(&id).map_or(0, |m| m.drop_order), b))
+            .collect();
+        // The sort is stable and a Vec drops its elements front to back.
+        entries.sort_by_key(|(order, _)| *order);
+    }
+}
+
 impl GothamStore {
     /// Puts a value into the `GothamStore`. One value of each type is retained.
     /// Successive calls to `put` will overwrite the existing value of the same
     /// type.
+    ///
+    /// If a value of the same type is already present, it is overwritten in
+    /// place and its allocation is reused.
     pub fn put<T: 'static>(&mut self, t: T) {
-        let type_id = TypeId::of::<T>();
-        self.data.insert(type_id, Box::new(t));
+        self.swap_value(t);
+    }
+
+    /// Puts an already boxed value into the `GothamStore` under the given
+    /// `TypeId`, returning the value it displaced, if any.
+    ///
+    /// `id` must be the `TypeId` of the boxed value; this is only checked in
+    /// debug builds.
+    pub fn insert_any(&mut self, id: TypeId, value: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        debug_assert!(
+            (*value).type_id() == id,
+            "TypeId does not match the type of the boxed value"
+        );
+        let meta = Meta {
+            name: self.type_name_of(id),
+            ..Meta::default()
+        };
+        self.insert_entry(id, value, meta)
     }
 
     /// Determines if the current value exists in `GothamStore`.
     pub fn has<T: 'static>(&self) -> bool {
         let type_id = TypeId::of::<T>();
-        self.data.contains_key(&type_id)
+        self.data.contains_key(&type_id) && !self.is_expired(type_id)
     }
 
     /// Tries to borrow a value from the `GothamStore`.
+    ///
+    /// In debug builds, this panics if the entry for `T` holds a value of a
+    /// different type, which can only result from misuse of
+    /// [`insert_any`](Self::insert_any). Type aliases share the `TypeId` of
+    /// the aliased type and are not affected.
     pub fn try_borrow<T: 'static>(&self) -> Option<&T> {
+        trace!("borrow", T);
         let type_id = TypeId::of::<T>();
+        self.debug_assert_type::<T>(type_id);
+        if self.is_expired(type_id) {
+            return None;
+        }
         self.data.get(&type_id).and_then(|b| b.downcast_ref())
     }
 
     /// Borrows a value from the `GothamStore`.
+    ///
+    /// If the value is missing, the default registered via
+    /// [`register_global_default`](Self::register_global_default) is borrowed
+    /// instead, if any.
     pub fn borrow<T: 'static>(&self) -> &T {
-        self.try_borrow().unwrap_or_else(|| missing::<T>())
+        self.try_borrow()
+            .or_else(|| self.global_default())
+            .unwrap_or_else(|| missing_with::<T>(self.missing_formatter.as_deref()))
+    }
+
+    /// Tries to borrow a value from the `GothamStore` through an adapter
+    /// function, e.g. to expose a single field of the stored value.
+    pub fn view<T: 'static, U: ?Sized>(&self, adapt: impl FnOnce(&T) -> &U) -> Option<&U> {
+        self.try_borrow::<T>().map(adapt)
     }
 
     /// Tries to mutably borrow a value from the `GothamStore`.
+    ///
+    /// The same debug check as for [`try_borrow`](Self::try_borrow) applies.
     pub fn try_borrow_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        trace!("borrow_mut", T);
         let type_id = TypeId::of::<T>();
+        self.debug_assert_type::<T>(type_id);
+        if self.expire(type_id) {
+            return None;
+        }
         self.data.get_mut(&type_id).and_then(|b| b.downcast_mut())
     }
 
     /// Mutably borrows a value from the `GothamStore`.
     pub fn borrow_mut<T: 'static>(&mut self) -> &mut T {
-        self.try_borrow_mut().unwrap_or_else(|| missing::<T>())
+        trace!("borrow_mut", T);
+        let type_id = TypeId::of::<T>();
+        self.debug_assert_type::<T>(type_id);
+        if self.expire(type_id) {
+            missing_with::<T>(self.missing_formatter.as_deref());
+        }
+        match self.data.get_mut(&type_id).and_then(|b| b.downcast_mut()) {
+            Some(t) => t,
+            None => missing_with::<T>(self.missing_formatter.as_deref()),
+        }
     }
 
     /// Tries to move a value out of the `GothamStore` and return ownership.
+    ///
+    /// Returns `None` if the type is [frozen](Self::freeze_type) or the
+    /// `GothamStore` is [sealed](Self::seal).
     pub fn try_take<T: 'static>(&mut self) -> Option<T> {
+        trace!("take", T);
         let type_id = TypeId::of::<T>();
-        self.data
-            .remove(&type_id)
-            .and_then(|b| b.downcast().ok())
+        if self.is_locked(&type_id) || self.expire(type_id) {
+            return None;
+        }
+        self.remove_entry(type_id)
+            .and_then(|(b, _)| b.downcast().ok())
             .map(|b| *b)
     }
 
     /// Moves a value out of the `GothamStore` and returns ownership.
     ///
+    /// If the value is missing, a clone of the default registered via
+    /// [`register_global_default`](Self::register_global_default) is returned
+    /// instead, if any.
+    ///
     /// # Panics
     ///
-    /// If a value of type `T` is not present in `GothamStore`.
+    /// If a value of type `T` is not present in `GothamStore` and has no
+    /// registered default, or if the type is [frozen](Self::freeze_type).
     pub fn take<T: 'static>(&mut self) -> T {
-        self.try_take().unwrap_or_else(|| missing::<T>())
+        self.check_frozen(TypeId::of::<T>());
+        self.try_take()
+            .or_else(|| {
+                let (default, clone) = self.defaults.get(&TypeId::of::<T>())?;
+                clone(default.as_ref()).downcast().ok().map(|b| *b)
+            })
+            .unwrap_or_else(|| missing_with::<T>(self.missing_formatter.as_deref()))
     }
-}
 
-fn missing<T: 'static>() -> ! {
-    panic!(
-        "required type {} is not present in GothamStore container",
-        type_name::<T>()
-    );
-}
+    /// Returns the number of values in the `GothamStore`. This takes constant
+    /// time.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::GothamStore;
+    /// Returns the number of values in the `GothamStore`. This is an alias for
+    /// [`len`](Self::len).
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.len()
+    }
 
-    struct MyStruct {
-        value: i32,
+    /// Returns `true` if the `GothamStore` contains no values. This takes
+    /// constant time.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
     }
 
-    struct AnotherStruct {
-        value: &'static str,
+    /// Mutably borrows a `Vec<T>` from the `GothamStore` and runs `f` on it,
+    /// returning `None` if no such `Vec` is present.
+    pub fn with_vec_mut<T: 'static, R>(&mut self, f: impl FnOnce(&mut Vec<T>) -> R) -> Option<R> {
+        self.try_borrow_mut::<Vec<T>>().map(f)
     }
 
-    type Alias1 = String;
-    type Alias2 = String;
+    /// Gets the entry for type `T` for in-place manipulation.
+    pub fn entry<T: 'static>(&mut self) -> Entry<'_, T> {
+        if self.has::<T>() {
+            Entry::Occupied(OccupiedEntry {
+                store: self,
+                marker: PhantomData,
+            })
+        } else {
+            Entry::Vacant(VacantEntry {
+                store: self,
+                marker: PhantomData,
+            })
+        }
+    }
 
-    #[test]
-    fn put_borrow1() {
-        let mut store = GothamStore::default();
-        store.put(MyStruct { value: 1 });
-        assert_eq!(store.borrow::<MyStruct>().value, 1);
+    /// Moves a value of type `T` from this `GothamStore` into `dest`,
+    /// returning `true` if a value was moved.
+    ///
+    /// Nothing is moved if the type is [frozen](Self::freeze_type) in this
+    /// `GothamStore` or the value has [expired](Self::put_with_ttl). A TTL
+    /// moves along with the value.
+    pub fn move_type_into<T: 'static>(&mut self, dest: &mut GothamStore) -> bool {
+        let type_id = TypeId::of::<T>();
+        if self.is_locked(&type_id) || self.expire(type_id) {
+            return false;
+        }
+        dest.check_frozen(type_id);
+        match self.remove_entry(type_id) {
+            Some((b, meta)) => {
+                dest.insert_entry(type_id, b, meta);
+                true
+            }
+            None => false,
+        }
     }
 
-    #[test]
-    fn put_borrow2() {
-        let mut store = GothamStore::default();
-        assert!(!store.has::<AnotherStruct>());
-        store.put(AnotherStruct { value: "a string" });
-        assert!(store.has::<AnotherStruct>());
-        assert!(!store.has::<MyStruct>());
-        store.put(MyStruct { value: 100 });
-        assert!(store.has::<MyStruct>());
-        assert_eq!(store.borrow::<MyStruct>().value, 100);
-        assert_eq!(store.borrow::<AnotherStruct>().value, "a string");
+    /// Asserts that a value of type `T` exists in `GothamStore`, returning
+    /// `self` so that assertions can be chained.
+    ///
+    /// # Panics
+    ///
+    /// If a value of type `T` is not present in `GothamStore`.
+    pub fn assert_present<T: 'static>(&self) -> &Self {
+        if !self.has::<T>() {
+            missing::<T>();
+        }
+        self
     }
 
-    #[test]
-    fn try_borrow() {
-        let mut store = GothamStore::default();
-        store.put(MyStruct { value: 100 });
-        assert!(store.try_borrow::<MyStruct>().is_some());
-        assert_eq!(store.try_borrow::<MyStruct>().unwrap().value, 100);
-        assert!(store.try_borrow::<AnotherStruct>().is_none());
+    /// Asserts that no value of type `T` exists in `GothamStore`, returning
+    /// `self` so that assertions can be chained.
+    ///
+    /// # Panics
+    ///
+    /// If a value of type `T` is present in `GothamStore`.
+    pub fn assert_absent<T: 'static>(&self) -> &Self {
+        if self.has::<T>() {
+            panic!(
+                "unexpected type {} is present in GothamStore container",
+                type_name::<T>()
+            );
+        }
+        self
     }
 
-    #[test]
-    fn try_borrow_mut() {
-        let mut store = GothamStore::default();
-        store.put(MyStruct { value: 100 });
-        if let Some(a) = store.try_borrow_mut::<MyStruct>() {
-            a.value += 10;
+    /// Borrows either a value of type `A` or a value of type `B` from the
+    /// `GothamStore`, preferring `A` if both are present.
+    pub fn borrow_either<A: 'static, B: 'static>(&self) -> OneOf<&A, &B> {
+        if let Some(a) = self.try_borrow::<A>() {
+            OneOf::A(a)
+        } else if let Some(b) = self.try_borrow::<B>() {
+            OneOf::B(b)
+        } else {
+            OneOf::Neither
         }
-        assert_eq!(store.borrow::<MyStruct>().value, 110);
     }
 
-    #[test]
-    fn borrow_mut() {
-        let mut store = GothamStore::default();
-        store.put(MyStruct { value: 100 });
-        {
-            let a = store.borrow_mut::<MyStruct>();
-            a.value += 10;
+    /// Borrows a value from the `GothamStore`, like [`borrow`](Self::borrow),
+    /// but includes `context` in the panic message if it is missing.
+    pub fn borrow_ctx<T: 'static>(&self, context: &str) -> &T {
+        self.try_borrow()
+            .unwrap_or_else(|| missing_ctx::<T>(context))
+    }
+
+    /// Moves a value out of the `GothamStore`, like [`take`](Self::take), but
+    /// includes `context` in the panic message if it is missing.
+    pub fn take_ctx<T: 'static>(&mut self, context: &str) -> T {
+        self.check_frozen(TypeId::of::<T>());
+        self.try_take().unwrap_or_else(|| missing_ctx::<T>(context))
+    }
+
+    /// Replaces the value of type `T` with the result of `f`, which receives
+    /// the current value, or `None` if there is none.
+    ///
+    /// The current value is moved into `f`, so it cannot be restored if `f`
+    /// panics: the value is dropped during unwinding and the `GothamStore` is
+    /// left without a value of type `T`.
+    pub fn replace_with<T: 'static>(&mut self, f: impl FnOnce(Option<T>) -> T) {
+        self.check_frozen(TypeId::of::<T>());
+        let value = f(self.try_take());
+        self.put(value);
+    }
+
+    /// Borrows a value from the `GothamStore`, or returns `default` if it is
+    /// missing.
+    pub fn borrow_or<'a, T: 'static>(&'a self, default: &'a T) -> &'a T {
+        self.try_borrow().unwrap_or(default)
+    }
+
+    /// Moves every value whose captured type name starts with `prefix` into a
+    /// new `GothamStore`, which is returned.
+    ///
+    /// Values without a captured type name and values of
+    /// [frozen](Self::freeze_type) types are left in place.
+    pub fn split_by_name_prefix(&mut self, prefix: &str) -> GothamStore {
+        self.expire_all();
+        let mut split = GothamStore::default();
+        for id in self.unfrozen_ids_by_name_prefix(prefix) {
+            if let Some((b, meta)) = self.remove_entry(id) {
+                split.insert_entry(id, b, meta);
+            }
         }
-        assert_eq!(store.borrow::<MyStruct>().value, 110);
-        assert!(store.try_borrow_mut::<AnotherStruct>().is_none());
+        split
     }
 
-    #[test]
-    fn try_take() {
-        let mut store = GothamStore::default();
-        store.put(MyStruct { value: 100 });
-        assert_eq!(store.try_take::<MyStruct>().unwrap().value, 100);
-        assert!(store.try_take::<MyStruct>().is_none());
-        assert!(store.try_borrow_mut::<MyStruct>().is_none());
-        assert!(store.try_borrow::<MyStruct>().is_none());
-        assert!(store.try_take::<AnotherStruct>().is_none());
+    /// Returns `true` if every type present in `schema` is also present in
+    /// this `GothamStore`.
+    pub fn contains_all_types_of(&self, schema: &GothamStore) -> bool {
+        schema.data.keys().all(|id| self.data.contains_key(id))
     }
 
-    #[test]
-    fn take() {
-        let mut store = GothamStore::default();
-        store.put(MyStruct { value: 110 });
-        assert_eq!(store.take::<MyStruct>().value, 110);
-        assert!(store.try_take::<MyStruct>().is_none());
-        assert!(store.try_borrow_mut::<MyStruct>().is_none());
-        assert!(store.try_borrow::<MyStruct>().is_none());
+    /// Returns the `TypeId` of every type present in `schema` but missing from
+    /// this `GothamStore`.
+    pub fn missing_types_of(&self, schema: &GothamStore) -> Vec<TypeId> {
+        schema
+            .data
+            .keys()
+            .filter(|id| !self.data.contains_key(id))
+            .copied()
+            .collect()
     }
 
-    #[test]
-    fn type_alias() {
-        let mut store = GothamStore::default();
-        store.put::<Alias1>("alias1".to_string());
-        store.put::<Alias2>("alias2".to_string());
-        assert_eq!(store.take::<Alias1>(), "alias2");
-        assert!(store.try_take::<Alias1>().is_none());
-        assert!(store.try_take::<Alias2>().is_none());
+    /// Mutates a value in the `GothamStore` with `modify` and then reads it with
+    /// `read`, looking it up only once. Returns `None` if it is missing.
+    pub fn modify_then_read<T: 'static, R>(
+        &mut self,
+        modify: impl FnOnce(&mut T),
+        read: impl FnOnce(&T) -> R,
+    ) -> Option<R> {
+        self.try_borrow_mut().map(|t| {
+            modify(t);
+            read(t)
+        })
     }
 
-    #[test]
-    #[should_panic(
-        expected = "required type gotham_store::tests::MyStruct is not present in GothamStore container"
-    )]
-    fn missing() {
-        let store = GothamStore::default();
-        let _ = store.borrow::<MyStruct>();
+    /// Puts a JSON value into the `GothamStore` under the given key. Successive
+    /// calls to `put_json` will overwrite the existing value of the same key.
+    #[cfg(feature = "serde")]
+    pub fn put_json(&mut self, key: &str, value: serde_json::Value) {
+        self.check_sealed();
+        self.json.insert(key.to_string(), value);
     }
 
-    #[test]
-    fn deref() {
-        let mut store = GothamStore::default();
-        assert!(store.is_empty());
-        assert_eq!(store.len(), 0);
-        store.put(MyStruct { value: 100 });
-        assert!(!store.is_empty());
-        assert_eq!(store.len(), 1);
-        store.take::<MyStruct>();
-        assert!(store.is_empty());
-        assert_eq!(store.len(), 0);
+    /// Tries to deserialize the JSON value stored under the given key into
+    /// `T`, returning `Ok(None)` if there is no such value.
+    #[cfg(feature = "serde")]
+    pub fn get_as<T: serde::de::DeserializeOwned>(
+        &self,
+        key: &str,
+    ) -> Result<Option<T>, StoreError> {
+        self.json
+            .get(key)
+            .map(T::deserialize)
+            .transpose()
+            .map_err(StoreError::Json)
+    }
+
+    /// Freezes type `T`, so that its value can no longer be put, replaced or
+    /// taken. Reads, including mutable borrows, remain allowed.
+    ///
+    /// Operations that would overwrite or remove a value of a frozen type
+    /// panic, except for the fallible ones like [`try_take`](Self::try_take),
+    /// which leave the value in place and report that nothing was taken.
+    pub fn freeze_type<T: 'static>(&mut self) {
+        self.frozen.insert(TypeId::of::<T>(), type_name::<T>());
+    }
+
+    /// Determines if type `T` is frozen in `GothamStore`.
+    pub fn is_frozen<T: 'static>(&self) -> bool {
+        self.frozen.contains_key(&TypeId::of::<T>())
+    }
+
+    /// Seals the `GothamStore`, so that no value can be put, replaced or taken
+    /// anymore. This applies to every type, as if each were
+    /// [frozen](Self::freeze_type), and cannot be undone.
+    pub fn seal(&mut self) {
+        self.sealed = true;
+    }
+
+    /// Determines if the `GothamStore` is [sealed](Self::seal).
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+
+    fn is_locked(&self, type_id: &TypeId) -> bool {
+        self.sealed || self.frozen.contains_key(type_id)
+    }
+
+    fn global_default<T: 'static>(&self) -> Option<&T> {
+        let (default, _) = self.defaults.get(&TypeId::of::<T>())?;
+        default.downcast_ref()
+    }
+
+    fn debug_assert_type<T: 'static>(&self, type_id: TypeId) {
+        debug_assert!(
+            !matches!(self.data.get(&type_id), Some(b) if !b.is::<T>()),
+            "entry for type {} holds a value of a different type in GothamStore container",
+            type_name::<T>()
+        );
+    }
+
+    fn check_sealed(&self) {
+        if self.sealed {
+            panic!("GothamStore container is sealed");
+        }
+    }
+
+    fn check_frozen(&self, type_id: TypeId) {
+        self.check_sealed();
+        if let Some(name) = self.frozen.get(&type_id) {
+            panic!("type {} is frozen in GothamStore container", name);
+        }
+    }
+
+    /// Returns the captured type names of the values in the `GothamStore`, in
+    /// `TypeId` order.
+    ///
+    /// Values without a captured type name are skipped.
+    pub fn type_names(&self) -> Vec<&'static str> {
+        self.data
+            .keys()
+            .filter_map(|id| self.type_name_of(*id))
+            .collect()
+    }
+
+    /// Returns a read-only view of the `GothamStore`.
+    pub fn readonly(&self) -> ReadOnlyStore<'_> {
+        ReadOnlyStore(self)
+    }
+
+    /// Clones values of types `A` and `B` out of the `GothamStore`, returning
+    /// `None` if either is missing.
+    pub fn clone_out<A: Clone + 'static, B: Clone + 'static>(&self) -> Option<(A, B)> {
+        Some((
+            self.try_borrow::<A>()?.clone(),
+            self.try_borrow::<B>()?.clone(),
+        ))
+    }
+
+    /// Clones values of types `A`, `B` and `C` out of the `GothamStore`,
+    /// returning `None` if any of them is missing.
+    pub fn clone_out3<A: Clone + 'static, B: Clone + 'static, C: Clone + 'static>(
+        &self,
+    ) -> Option<(A, B, C)> {
+        Some((
+            self.try_borrow::<A>()?.clone(),
+            self.try_borrow::<B>()?.clone(),
+            self.try_borrow::<C>()?.clone(),
+        ))
+    }
+
+    /// Returns the size in bytes of each value in the `GothamStore`, in
+    /// `TypeId` order.
+    ///
+    /// Only the value itself is measured, not any heap memory it owns.
+    pub fn entry_sizes(&self) -> Vec<(TypeId, usize)> {
+        self.data
+            .iter()
+            .map(|(id, b)| (*id, std::mem::size_of_val(b.as_ref())))
+            .collect()
+    }
+
+    /// Puts a value into the `GothamStore`, like [`put`](Self::put), and
+    /// returns a mutable reference to it.
+    pub fn put_ref<T: 'static>(&mut self, t: T) -> &mut T {
+        self.put_slot(t).0
+    }
+
+    /// Calls `f` with the `TypeId` and a mutable reference to each value in the
+    /// `GothamStore`, in `TypeId` order.
+    pub fn visit_mut(&mut self, mut f: impl FnMut(TypeId, &mut dyn Any)) {
+        for (id, b) in self.data.iter_mut() {
+            f(*id, b.as_mut());
+        }
+    }
+
+    /// Tries to borrow values of types `A` and `B` from the `GothamStore`.
+    pub fn get2<A: 'static, B: 'static>(&self) -> (Option<&A>, Option<&B>) {
+        (self.try_borrow(), self.try_borrow())
+    }
+
+    /// Tries to borrow values of types `A`, `B` and `C` from the
+    /// `GothamStore`.
+    pub fn get3<A: 'static, B: 'static, C: 'static>(&self) -> (Option<&A>, Option<&B>, Option<&C>) {
+        (self.try_borrow(), self.try_borrow(), self.try_borrow())
+    }
+
+    /// Mutably borrows a value from the `GothamStore`, putting
+    /// `T::store_default()` into it first if it is missing.
+    pub fn borrow_or_store_default<T: StoreDefault + 'static>(&mut self) -> &mut T {
+        self.entry().or_insert_with(T::store_default)
+    }
+
+    /// Moves the value of type `T` out of the `GothamStore` and puts it back
+    /// wrapped as type `W`, e.g. in a newtype. Returns `false` if no value of
+    /// type `T` is present.
+    ///
+    /// # Panics
+    ///
+    /// If type `T` or `W` is [frozen](Self::freeze_type) or the `GothamStore`
+    /// is [sealed](Self::seal), in which case the value of type `T` is left
+    /// in place.
+    pub fn rewrap<T: 'static, W: 'static>(&mut self, wrap: impl FnOnce(T) -> W) -> bool {
+        self.check_frozen(TypeId::of::<T>());
+        self.check_frozen(TypeId::of::<W>());
+        match self.try_take::<T>() {
+            Some(t) => {
+                self.put(wrap(t));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the number of values whose captured type name starts with
+    /// `prefix`.
+    pub fn count_by_name_prefix(&self, prefix: &str) -> usize {
+        self.data
+            .keys()
+            .filter_map(|id| self.type_name_of(*id))
+            .filter(|name| name.starts_with(prefix))
+            .count()
+    }
+
+    fn type_name_of(&self, type_id: TypeId) -> Option<&'static str> {
+        self.meta.get(&type_id).and_then(|m| m.name)
+    }
+
+    fn insert_entry(
+        &mut self,
+        type_id: TypeId,
+        value: Box<dyn Any>,
+        meta: Meta,
+    ) -> Option<Box<dyn Any>> {
+        self.check_frozen(type_id);
+        self.insert_meta(type_id, meta);
+        let old = self.data.insert(type_id, value);
+        self.notify(type_id);
+        old
+    }
+
+    fn insert_meta(&mut self, type_id: TypeId, meta: Meta) {
+        #[cfg(feature = "timestamps")]
+        let meta = Meta {
+            inserted: Some(Instant::now()),
+            ..meta
+        };
+        self.meta.insert(type_id, meta);
+    }
+
+    fn is_expired(&self, type_id: TypeId) -> bool {
+        matches!(self.meta.get(&type_id), Some(Meta { expires: Some(at), .. }) if *at <= Instant::now())
+    }
+
+    /// Removes the value of the given type if its TTL has passed, unless the
+    /// type is locked, and returns whether it has expired.
+    fn expire(&mut self, type_id: TypeId) -> bool {
+        let expired = self.is_expired(type_id);
+        if expired && !self.is_locked(&type_id) {
+            self.remove_entry(type_id);
+        }
+        expired
+    }
+
+    fn expire_all(&mut self) {
+        let ids: Vec<TypeId> = self.data.keys().copied().collect();
+        for id in ids {
+            self.expire(id);
+        }
+    }
+
+    fn notify(&mut self, type_id: TypeId) {
+        if let Some(observer) = self.observers.get(&type_id) {
+            observer(self.data[&type_id].as_ref());
+        }
+        self.send_to_watchers(type_id);
+    }
+
+    fn send_to_watchers(&mut self, type_id: TypeId) {
+        self.watchers.retain(|w| w.send(type_id).is_ok());
+    }
+
+    fn remove_entry(&mut self, type_id: TypeId) -> Option<(Box<dyn Any>, Meta)> {
+        let b = self.data.remove(&type_id)?;
+        self.send_to_watchers(type_id);
+        Some((b, self.meta.remove(&type_id).unwrap_or_default()))
+    }
+
+    /// Puts a value that is `Send + Sync` into the `GothamStore`, like
+    /// [`put`](Self::put), and records it as such so that the store can later
+    /// be converted with [`into_sync`](Self::into_sync).
+    pub fn put_sync<T: Send + Sync + 'static>(&mut self, t: T) {
+        trace!("put", T);
+        let meta = Meta {
+            to_sync: Some(|b| b.downcast::<T>().unwrap()),
+            ..Meta::of::<T>()
+        };
+        self.insert_entry(TypeId::of::<T>(), Box::new(t), meta);
+    }
+
+    /// Converts the `GothamStore` into a [`SyncGothamStore`], which can be
+    /// shared between threads.
+    ///
+    /// This only succeeds if every value was inserted via
+    /// [`put_sync`](Self::put_sync); otherwise the `GothamStore` is returned
+    /// unchanged.
+    #[allow(clippy::result_large_err)]
+    pub fn into_sync(mut self) -> Result<SyncGothamStore, GothamStore> {
+        let all_sync = self
+            .data
+            .keys()
+            .all(|id| matches!(self.meta.get(id), Some(m) if m.to_sync.is_some()));
+        if !all_sync {
+            return Err(self);
+        }
+        let data = std::mem::take(&mut self.data)
+            .into_iter()
+            .map(|(id, b)| (id, (self.meta[&id].to_sync.unwrap())(b)))
+            .collect();
+        Ok(SyncGothamStore { data })
+    }
+
+    /// Returns a raw pointer to a value in the `GothamStore`, or `None` if it is
+    /// missing.
+    ///
+    /// The pointer is only valid for as long as the value stays in the store:
+    /// overwriting, taking or otherwise removing it, as well as dropping the
+    /// store, invalidates the pointer.
+    pub fn as_ptr<T: 'static>(&self) -> Option<*const T> {
+        self.try_borrow::<T>().map(|t| t as *const T)
+    }
+
+    /// Returns a raw mutable pointer to a value in the `GothamStore`, or `None`
+    /// if it is missing.
+    ///
+    /// The same validity rules as for [`as_ptr`](Self::as_ptr) apply. In
+    /// addition, the pointee must not be accessed through the pointer while it
+    /// is borrowed from the store.
+    pub fn as_mut_ptr<T: 'static>(&mut self) -> Option<*mut T> {
+        self.try_borrow_mut::<T>().map(|t| t as *mut T)
+    }
+
+    /// Removes the values of all the given types from the `GothamStore`,
+    /// returning how many were actually removed.
+    ///
+    /// Values of [frozen](Self::freeze_type) types are left in place.
+    pub fn remove_many(&mut self, ids: &[TypeId]) -> usize {
+        let mut removed = 0;
+        for id in ids {
+            if !self.is_locked(id) && self.remove_entry(*id).is_some() {
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Writes the captured type names of the values in the `GothamStore` to
+    /// `w`, one per line, in `TypeId` order.
+    ///
+    /// Values without a captured type name are skipped.
+    pub fn write_type_names(&self, w: &mut impl std::fmt::Write) -> std::fmt::Result {
+        for name in self.data.keys().filter_map(|id| self.type_name_of(*id)) {
+            writeln!(w, "{}", name)?;
+        }
+        Ok(())
+    }
+
+    /// Tries to move a value out of the `GothamStore` behind a [`TakeGuard`],
+    /// which puts the value back when dropped unless it is
+    /// [committed](TakeGuard::commit).
+    ///
+    /// Returns `None` if the value is missing or has
+    /// [expired](Self::put_with_ttl), or the type is
+    /// [frozen](Self::freeze_type).
+    pub fn take_guard<T: 'static>(&mut self) -> Option<TakeGuard<'_, T>> {
+        let type_id = TypeId::of::<T>();
+        if self.is_locked(&type_id) || self.expire(type_id) {
+            return None;
+        }
+        let (b, meta) = self.remove_entry(type_id)?;
+        let value = b.downcast().ok()?;
+        Some(TakeGuard {
+            store: self,
+            taken: Some((value, meta)),
+        })
+    }
+
+    /// Puts an already boxed value into the `GothamStore` under its own
+    /// `TypeId`, returning the value it displaced, if any.
+    pub fn replace_any(&mut self, value: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        let id = (*value).type_id();
+        self.insert_any(id, value)
+    }
+
+    /// Calls `f` with the `TypeId` and a reference to each value in the
+    /// `GothamStore`, in `TypeId` order, and collects the results that are
+    /// `Some`.
+    pub fn collect_map<R>(&self, f: impl Fn(TypeId, &dyn Any) -> Option<R>) -> Vec<R> {
+        self.data
+            .iter()
+            .filter_map(|(id, b)| f(*id, b.as_ref()))
+            .collect()
+    }
+
+    /// Returns a builder for registering typed observers, which are invoked
+    /// with every value of their type that is put into the `GothamStore`.
+    pub fn observers(&mut self) -> Observers<'_> {
+        Observers { store: self }
+    }
+
+    /// Promotes an owned value of type `T` to a shared `Rc<T>`: the value is
+    /// moved out of the `GothamStore`, wrapped in an `Rc`, and put back as
+    /// `Rc<T>`. Returns a clone of that `Rc`, or `None` if the value is
+    /// missing.
+    ///
+    /// The promotion is one-way: afterwards the value is only available as
+    /// `Rc<T>`, e.g. via [`get_shared`](Self::get_shared).
+    pub fn into_shared<T: 'static>(&mut self) -> Option<Rc<T>> {
+        let shared = Rc::new(self.try_take::<T>()?);
+        self.put(shared.clone());
+        Some(shared)
+    }
+
+    /// Returns a clone of the `Rc<T>` in the `GothamStore`, or `None` if it is
+    /// missing.
+    pub fn get_shared<T: 'static>(&self) -> Option<Rc<T>> {
+        self.try_borrow::<Rc<T>>().cloned()
+    }
+
+    /// Removes every value whose captured type name starts with `prefix`,
+    /// returning how many were removed.
+    ///
+    /// Values without a captured type name and values of
+    /// [frozen](Self::freeze_type) types are left in place.
+    pub fn remove_by_name_prefix(&mut self, prefix: &str) -> usize {
+        let ids = self.unfrozen_ids_by_name_prefix(prefix);
+        for id in &ids {
+            self.remove_entry(*id);
+        }
+        ids.len()
+    }
+
+    fn unfrozen_ids_by_name_prefix(&self, prefix: &str) -> Vec<TypeId> {
+        self.data
+            .keys()
+            .filter(|id| !self.is_locked(id))
+            .filter(|id| matches!(self.type_name_of(**id), Some(name) if name.starts_with(prefix)))
+            .copied()
+            .collect()
+    }
+
+    /// Returns the number of values per originating crate, as derived from the
+    /// leading path segment of each captured type name.
+    ///
+    /// Types without a path, such as primitives, are counted under their
+    /// name, and compound types that do not start with a path, such as tuples,
+    /// slices and references, under their full name. Values without a
+    /// captured type name are skipped.
+    pub fn counts_by_crate(&self) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for name in self.data.keys().filter_map(|id| self.type_name_of(*id)) {
+            let end = name
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(name.len());
+            let krate = if end > 0 && name[end..].starts_with("::") {
+                &name[..end]
+            } else {
+                name
+            };
+            *counts.entry(krate.to_string()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Replaces the value of type `T` with `new` if `pred` holds for the
+    /// current value, returning the old value.
+    ///
+    /// If there is no current value, `new` is put into the `GothamStore` and
+    /// `Ok(None)` is returned. If `pred` does not hold, the `GothamStore` is
+    /// left unchanged and `new` is returned as the error.
+    pub fn replace_if<T: 'static>(
+        &mut self,
+        new: T,
+        pred: impl FnOnce(&T) -> bool,
+    ) -> Result<Option<T>, T> {
+        if let Some(old) = self.try_borrow::<T>() {
+            if !pred(old) {
+                return Err(new);
+            }
+        }
+        let old = self.try_take();
+        self.put(new);
+        Ok(old)
+    }
+
+    /// Returns the set of `TypeId`s present in the `GothamStore`, which can be
+    /// compared with a later snapshot via [`added_between`].
+    pub fn type_id_snapshot(&self) -> BTreeSet<TypeId> {
+        self.data.keys().copied().collect()
+    }
+
+    /// Puts `T::from_env()` into the `GothamStore` if it returns a value,
+    /// returning whether it did.
+    pub fn load_env<T: FromEnv + 'static>(&mut self) -> bool {
+        match T::from_env() {
+            Some(t) => {
+                self.put(t);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes every value for which `pred` returns `true` and returns the
+    /// removed values, in `TypeId` order.
+    ///
+    /// Values of [frozen](Self::freeze_type) types are left in place and not
+    /// passed to `pred`.
+    pub fn drain_filter(
+        &mut self,
+        mut pred: impl FnMut(TypeId, &dyn Any) -> bool,
+    ) -> Vec<(TypeId, Box<dyn Any>)> {
+        self.expire_all();
+        let ids: Vec<TypeId> = self
+            .data
+            .iter()
+            .filter(|(id, _)| !self.is_locked(id))
+            .filter(|(id, b)| pred(**id, b.as_ref()))
+            .map(|(id, _)| *id)
+            .collect();
+        ids.into_iter()
+            .filter_map(|id| self.remove_entry(id).map(|(b, _)| (id, b)))
+            .collect()
+    }
+
+    /// Puts a hashable value into the `GothamStore`, like [`put`](Self::put),
+    /// and records it as such so that it contributes to
+    /// [`content_hash`](Self::content_hash).
+    pub fn put_hashable<T: Hash + 'static>(&mut self, t: T) {
+        trace!("put", T);
+        let meta = Meta {
+            hash: Some(|value, state| value.downcast_ref::<T>().unwrap().hash(state)),
+            ..Meta::of::<T>()
+        };
+        self.insert_entry(TypeId::of::<T>(), Box::new(t), meta);
+    }
+
+    /// Returns a hash of all values inserted via
+    /// [`put_hashable`](Self::put_hashable), fed to the hasher in `TypeId`
+    /// order.
+    ///
+    /// All other values are skipped, so changes to them are not reflected in
+    /// the hash. A value stops contributing once it is overwritten via
+    /// another method such as `put`.
+    pub fn content_hash(&self) -> u64 {
+        let mut state = DefaultHasher::new();
+        for (id, b) in &self.data {
+            if let Some(hash) = self.meta.get(id).and_then(|m| m.hash) {
+                id.hash(&mut state);
+                hash(b.as_ref(), &mut state);
+            }
+        }
+        state.finish()
+    }
+
+    /// Tries to borrow a `Vec<T>` from the `GothamStore` as a slice.
+    pub fn borrow_as_slice<T: 'static>(&self) -> Option<&[T]> {
+        self.try_borrow::<Vec<T>>().map(Vec::as_slice)
+    }
+
+    /// Moves the `Rc<T>` out of the `GothamStore` and returns it, so that the
+    /// store no longer holds a strong reference to the shared value.
+    pub fn release_shared<T: 'static>(&mut self) -> Option<Rc<T>> {
+        self.try_take::<Rc<T>>()
+    }
+
+    /// Returns `true` if this `GothamStore` and `other` agree on type `T`: either
+    /// both lack a value of type `T`, or both hold equal values.
+    pub fn eq_on<T: PartialEq + 'static>(&self, other: &GothamStore) -> bool {
+        self.try_borrow::<T>() == other.try_borrow::<T>()
+    }
+
+    /// Puts a value into the `GothamStore`, like [`put`](Self::put), and
+    /// returns `self` so that insertions can be chained.
+    pub fn insert<T: 'static>(&mut self, t: T) -> &mut Self {
+        self.put(t);
+        self
+    }
+
+    /// Borrows a derived value of type `T`, computing it with `compute` on
+    /// first access.
+    ///
+    /// Derived values live in a cache separate from the values put into the
+    /// `GothamStore`, which is filled through a shared reference using
+    /// interior mutability. Cached values are only dropped by
+    /// [`clear_cached`](Self::clear_cached) or together with the store.
+    pub fn compute_cached<T: 'static>(&self, compute: impl FnOnce() -> T) -> &T {
+        let type_id = TypeId::of::<T>();
+        let cached = self
+            .cache
+            .borrow()
+            .get(&type_id)
+            .map(|b| b.as_ref() as *const dyn Any);
+        let ptr = cached.unwrap_or_else(|| {
+            let value = Box::new(compute());
+            // `compute` may have cached a value of type `T` itself, which must
+            // be kept because it may already be borrowed.
+            let mut cache = self.cache.borrow_mut();
+            cache.entry(type_id).or_insert(value).as_ref() as *const dyn Any
+        });
+        // SAFETY: Cached values are boxed, so their address is stable, and they
+        // are never replaced or removed while `self` is borrowed: the cache
+        // only grows through shared references and is cleared through
+        // `&mut self` only.
+        unsafe { &*ptr }.downcast_ref().unwrap()
+    }
+
+    /// Drops all derived values cached by
+    /// [`compute_cached`](Self::compute_cached).
+    pub fn clear_cached(&mut self) {
+        self.cache.get_mut().clear();
+    }
+
+    /// Calls `f` with the captured type name of each value in the
+    /// `GothamStore`, in `TypeId` order.
+    ///
+    /// Values without a captured type name are skipped.
+    pub fn for_each_type_name(&self, mut f: impl FnMut(&'static str)) {
+        for name in self.data.keys().filter_map(|id| self.type_name_of(*id)) {
+            f(name);
+        }
+    }
+
+    /// Tries to borrow a value from the `GothamStore`, removing the entry for
+    /// type `T` if it holds a value of a different type.
+    ///
+    /// Such entries can only result from misuse of
+    /// [`insert_any`](Self::insert_any).
+    pub fn borrow_or_drop<T: 'static>(&mut self) -> Option<&T> {
+        let type_id = TypeId::of::<T>();
+        if matches!(self.data.get(&type_id), Some(b) if !b.is::<T>()) {
+            self.remove_entry(type_id);
+        }
+        self.try_borrow()
+    }
+
+    /// Returns aggregate metadata about the `GothamStore`, computed in a
+    /// single pass.
+    pub fn stats(&self) -> StoreStats {
+        let mut stats = StoreStats::default();
+        for (id, b) in &self.data {
+            stats.entry_count += 1;
+            stats.total_value_bytes += std::mem::size_of_val(b.as_ref());
+            stats.type_names.extend(self.type_name_of(*id));
+        }
+        stats
+    }
+
+    /// Moves every value from this `GothamStore` into `dest`, overwriting
+    /// values of the same type there.
+    ///
+    /// Values of types that are [frozen](Self::freeze_type) in either store,
+    /// and all values if `dest` is [sealed](Self::seal), are left in place; all
+    /// others are moved.
+    pub fn move_all_into(&mut self, dest: &mut GothamStore) {
+        self.expire_all();
+        let ids: Vec<TypeId> = self
+            .data
+            .keys()
+            .filter(|id| !self.is_locked(id) && !dest.is_locked(id))
+            .copied()
+            .collect();
+        for id in ids {
+            if let Some((b, meta)) = self.remove_entry(id) {
+                dest.insert_entry(id, b, meta);
+            }
+        }
+    }
+
+    /// Puts an already boxed value into the `GothamStore` under the given
+    /// `TypeId`, like [`insert_any`](Self::insert_any), but checks that `id`
+    /// is the `TypeId` of the boxed value in all builds.
+    ///
+    /// Instead of panicking, this returns [`StoreError::Sealed`] if the
+    /// `GothamStore` is [sealed](Self::seal) and [`StoreError::Frozen`] if the
+    /// type is [frozen](Self::freeze_type).
+    pub fn try_insert_any(
+        &mut self,
+        id: TypeId,
+        value: Box<dyn Any>,
+    ) -> Result<Option<Box<dyn Any>>, StoreError> {
+        if (*value).type_id() != id {
+            return Err(StoreError::TypeMismatch);
+        }
+        if self.sealed {
+            return Err(StoreError::Sealed);
+        }
+        if let Some(name) = self.frozen.get(&id) {
+            return Err(StoreError::Frozen(name));
+        }
+        Ok(self.insert_any(id, value))
+    }
+
+    /// Returns the `TypeId`s of all values in the `GothamStore`, sorted in
+    /// ascending order so that the result can be binary searched.
+    ///
+    /// The order is guaranteed regardless of the map backing the store.
+    pub fn sorted_type_ids(&self) -> Vec<TypeId> {
+        // The BTreeMap already yields its keys in ascending order.
+        self.data.keys().copied().collect()
+    }
+
+    /// Puts a value into the `GothamStore`, like [`put`](Self::put), with a
+    /// priority that determines when it is dropped along with the store.
+    ///
+    /// Values are dropped in ascending `order`; values put by other means have
+    /// a priority of `0`.
+    pub fn put_ordered<T: 'static>(&mut self, order: i32, t: T) {
+        trace!("put", T);
+        let meta = Meta {
+            drop_order: order,
+            ..Meta::of::<T>()
+        };
+        self.insert_entry(TypeId::of::<T>(), Box::new(t), meta);
+    }
+
+    /// Sets the function used by [`borrow`](Self::borrow),
+    /// [`borrow_mut`](Self::borrow_mut) and [`take`](Self::take) to build the
+    /// panic message for a missing type from its name.
+    pub fn set_missing_formatter(&mut self, f: impl Fn(&'static str) -> String + 'static) {
+        self.missing_formatter = Some(Box::new(f));
+    }
+
+    /// Returns an iterator yielding the value of type `T` if it is present in
+    /// the `GothamStore`, and nothing otherwise.
+    pub fn iter_type<T: 'static>(&self) -> impl Iterator<Item = &T> {
+        self.try_borrow::<T>().into_iter()
+    }
+
+    /// Returns the JSON values put via [`put_json`](Self::put_json) as
+    /// `(key, value)` pairs sorted by key, e.g. for deterministic snapshots.
+    ///
+    /// The entries can be put back into a `GothamStore` with `put_json`.
+    #[cfg(feature = "serde")]
+    pub fn to_sorted_entries(&self) -> Vec<(String, serde_json::Value)> {
+        self.json
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Returns a receiver that is sent the `TypeId` of every value that is
+    /// subsequently put into, replaced in or removed from the `GothamStore`.
+    ///
+    /// Any number of receivers can be active; dropped receivers are pruned on
+    /// the next change.
+    pub fn watch(&mut self) -> Receiver<TypeId> {
+        let (sender, receiver) = mpsc::channel();
+        self.watchers.push(sender);
+        receiver
+    }
+
+    /// Mutably borrows values of the distinct types `A` and `B` from the
+    /// `GothamStore` at the same time.
+    ///
+    /// Returns [`StoreError::SameType`] if `A` and `B` are the same type, or
+    /// [`StoreError::Missing`] naming the first type that is not present or
+    /// has [expired](Self::put_with_ttl).
+    pub fn borrow_pair_mut_checked<A: 'static, B: 'static>(
+        &mut self,
+    ) -> Result<(&mut A, &mut B), StoreError> {
+        let (a_id, b_id) = (TypeId::of::<A>(), TypeId::of::<B>());
+        if a_id == b_id {
+            return Err(StoreError::SameType);
+        }
+        let (a_expired, b_expired) = (self.expire(a_id), self.expire(b_id));
+        let (mut a, mut b) = (None, None);
+        for (id, value) in self.data.iter_mut() {
+            if *id == a_id && !a_expired {
+                a = value.downcast_mut::<A>();
+            } else if *id == b_id && !b_expired {
+                b = value.downcast_mut::<B>();
+            }
+        }
+        match (a, b) {
+            (Some(a), Some(b)) => Ok((a, b)),
+            (None, _) => Err(StoreError::Missing(type_name::<A>())),
+            (_, None) => Err(StoreError::Missing(type_name::<B>())),
+        }
+    }
+
+    /// Borrows the value of type `T`, first constructing it via
+    /// [`Provide::provide`] if it is missing. The dependencies of `T` are
+    /// resolved the same way beforehand, so they are built in dependency order.
+    ///
+    /// # Panics
+    ///
+    /// If the dependencies of `T` form a cycle.
+    pub fn resolve<T: Provide + 'static>(&mut self) -> &T {
+        let type_id = TypeId::of::<T>();
+        if !self.has::<T>() {
+            if self.resolving.iter().any(|(id, _)| *id == type_id) {
+                let path: Vec<_> = self.resolving.iter().map(|(_, name)| *name).collect();
+                panic!(
+                    "dependency cycle detected in GothamStore container: {} -> {}",
+                    path.join(" -> "),
+                    type_name::<T>()
+                );
+            }
+            let len = self.resolving.len();
+            self.resolving.push((type_id, type_name::<T>()));
+            let guard = ResolvingGuard { store: self, len };
+            T::Deps::resolve_all(guard.store);
+            let t = T::provide(guard.store);
+            drop(guard);
+            self.put(t);
+        }
+        self.borrow()
+    }
+
+    /// Returns the [`KeyedStore`] holding the values put under a key.
+    pub fn keyed(&self) -> &KeyedStore {
+        &self.keyed
+    }
+
+    /// Mutably borrows the value of type `T` stored under the given key,
+    /// putting the result of `f` there first if it is missing.
+    ///
+    /// # Panics
+    ///
+    /// If the value is missing and the `GothamStore` is
+    /// [sealed](Self::seal).
+    pub fn keyed_get_or_insert_with<T: 'static>(
+        &mut self,
+        key: impl Into<String>,
+        f: impl FnOnce() -> T,
+    ) -> &mut T {
+        let key = key.into();
+        if self.keyed.get_keyed::<T>(&key).is_none() {
+            self.check_sealed();
+        }
+        self.keyed.get_or_insert_with(key, f)
+    }
+
+    /// Mutably borrows the value of type `T` stored under the given key,
+    /// putting `T::default()` there first if it is missing.
+    ///
+    /// # Panics
+    ///
+    /// If the value is missing and the `GothamStore` is
+    /// [sealed](Self::seal).
+    pub fn keyed_entry<T: Default + 'static>(&mut self, key: impl Into<String>) -> &mut T {
+        self.keyed_get_or_insert_with(key, T::default)
+    }
+
+    /// Checks that every value in the `GothamStore` is stored under its own
+    /// `TypeId`, returning [`StoreError::Inconsistent`] for the first one that
+    /// is not.
+    ///
+    /// Such entries can only result from misuse of
+    /// [`insert_any`](Self::insert_any), which checks this in debug builds
+    /// only.
+    pub fn validate(&self) -> Result<(), StoreError> {
+        for (key, b) in &self.data {
+            let actual = (**b).type_id();
+            if actual != *key {
+                return Err(StoreError::Inconsistent { key: *key, actual });
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts a `Weak<T>` into the `GothamStore`, like [`put`](Self::put), and
+    /// records it as such so that it can be removed by
+    /// [`prune_dead_weaks`](Self::prune_dead_weaks) once its referent is gone.
+    pub fn put_weak<T: 'static>(&mut self, weak: Weak<T>) {
+        trace!("put", Weak<T>);
+        let meta = Meta {
+            is_live: Some(|b| b.downcast_ref::<Weak<T>>().unwrap().strong_count() > 0),
+            ..Meta::of::<Weak<T>>()
+        };
+        self.insert_entry(TypeId::of::<Weak<T>>(), Box::new(weak), meta);
+    }
+
+    /// Removes every `Weak` put via [`put_weak`](Self::put_weak) whose
+    /// referent has been dropped, returning how many were removed.
+    ///
+    /// Values of [frozen](Self::freeze_type) types are left in place.
+    pub fn prune_dead_weaks(&mut self) -> usize {
+        let dead: Vec<_> = self
+            .data
+            .iter()
+            .filter(|(id, b)| match self.meta.get(id).and_then(|m| m.is_live) {
+                Some(is_live) => !is_live(b.as_ref()),
+                None => false,
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        self.remove_many(&dead)
+    }
+
+    /// Registers a default value for type `T`, which [`borrow`](Self::borrow)
+    /// and [`take`](Self::take) fall back to instead of panicking when no value
+    /// of type `T` is present.
+    ///
+    /// The default is not put into the `GothamStore`, so the other methods,
+    /// like [`try_borrow`](Self::try_borrow), are not affected.
+    pub fn register_global_default<T: Clone + 'static>(&mut self, default: T) {
+        let clone: CloneValue = |b| Box::new(b.downcast_ref::<T>().unwrap().clone());
+        self.defaults
+            .insert(TypeId::of::<T>(), (Box::new(default), clone));
+    }
+
+    /// Replaces the boxed value stored under `id` with the result of `f`,
+    /// which receives the current value. Returns `Ok(false)` if there is no
+    /// such value.
+    ///
+    /// If the new value is of a different type, the entry for `id` is removed
+    /// and the new value is put under its own `TypeId` instead, as if by
+    /// [`insert_any`](Self::insert_any). If that type is
+    /// [frozen](Self::freeze_type), the new value is returned as `Err` rather
+    /// than put, and the `GothamStore` is left without the original value. As
+    /// with [`replace_with`](Self::replace_with), the value is lost if `f`
+    /// panics.
+    ///
+    /// # Panics
+    ///
+    /// If the type stored under `id` is frozen or the `GothamStore` is
+    /// [sealed](Self::seal).
+    pub fn map_any(
+        &mut self,
+        id: TypeId,
+        f: impl FnOnce(Box<dyn Any>) -> Box<dyn Any>,
+    ) -> Result<bool, Box<dyn Any>> {
+        self.check_frozen(id);
+        let Some((b, meta)) = self.remove_entry(id) else {
+            return Ok(false);
+        };
+        let value = f(b);
+        let new_id = (*value).type_id();
+        if new_id == id {
+            self.insert_entry(id, value, meta);
+        } else if self.is_locked(&new_id) {
+            return Err(value);
+        } else {
+            self.insert_any(new_id, value);
+        }
+        Ok(true)
+    }
+
+    /// Returns how many values in the `GothamStore` have a `TypeId` that
+    /// satisfies `pred`.
+    pub fn count_where(&self, pred: impl Fn(TypeId) -> bool) -> usize {
+        self.data.keys().filter(|id| pred(**id)).count()
+    }
+
+    /// Borrows a value from the `GothamStore`, or returns the result of
+    /// `fallback` if it is missing, without putting it into the store.
+    pub fn borrow_cow<T: Clone + 'static>(&self, fallback: impl FnOnce() -> T) -> Cow<'_, T> {
+        match self.try_borrow() {
+            Some(t) => Cow::Borrowed(t),
+            None => Cow::Owned(fallback()),
+        }
+    }
+
+    /// Returns when the value of type `T` was put into the `GothamStore`, or
+    /// `None` if it is missing.
+    #[cfg(feature = "timestamps")]
+    pub fn insert_instant<T: 'static>(&self) -> Option<Instant> {
+        self.meta.get(&TypeId::of::<T>())?.inserted
+    }
+
+    /// Removes every value that was put into the `GothamStore` more than `d`
+    /// ago, returning how many were removed.
+    ///
+    /// Values of [frozen](Self::freeze_type) types are left in place.
+    #[cfg(feature = "timestamps")]
+    pub fn evict_older_than_duration(&mut self, d: Duration) -> usize {
+        let aged: Vec<_> = self
+            .meta
+            .iter()
+            .filter(|(_, m)| matches!(m.inserted, Some(at) if at.elapsed() > d))
+            .map(|(id, _)| *id)
+            .collect();
+        self.remove_many(&aged)
+    }
+
+    /// Puts a value into the `GothamStore`, like [`put`](Self::put), that
+    /// expires once `ttl` has passed.
+    ///
+    /// Expiry is lazy: an expired value is treated as missing by
+    /// [`has`](Self::has), [`try_borrow`](Self::try_borrow) and friends, and
+    /// is removed the next time it is mutably borrowed, taken or moved. The
+    /// TTL moves along with the value into another `GothamStore`. Values put
+    /// by other means never expire.
+    pub fn put_with_ttl<T: 'static>(&mut self, t: T, ttl: Duration) {
+        let type_id = TypeId::of::<T>();
+        self.put(t);
+        self.meta.entry(type_id).or_default().expires = Some(Instant::now() + ttl);
+    }
+
+    /// Tries to move a value out of the `GothamStore`, like
+    /// [`try_take`](Self::try_take), together with whether the store is empty
+    /// afterwards.
+    pub fn take_with_empty_flag<T: 'static>(&mut self) -> Option<(T, bool)> {
+        let t = self.try_take()?;
+        Some((t, self.is_empty()))
+    }
+
+    /// Creates a `GothamStore` by running each of the given providers against
+    /// an empty store, in order. Providers typically [`put`](Self::put) the
+    /// values of their type(s).
+    pub fn from_providers(providers: impl IntoIterator<Item = Provider>) -> Self {
+        let mut store = GothamStore::default();
+        for provide in providers {
+            provide(&mut store);
+        }
+        store
+    }
+
+    /// Puts a value into the `GothamStore`, like [`put`](Self::put), and
+    /// returns the value it displaced, if any.
+    ///
+    /// An existing value is swapped with `value` in place, so its allocation
+    /// is reused. A value whose [TTL](Self::put_with_ttl) has passed is
+    /// dropped instead of returned.
+    pub fn swap_value<T: 'static>(&mut self, value: T) -> Option<T> {
+        self.put_slot(value).1
+    }
+
+    /// Puts a value into the slot for type `T`, reusing an existing
+    /// allocation, and returns the slot along with the displaced value.
+    fn put_slot<T: 'static>(&mut self, value: T) -> (&mut T, Option<T>) {
+        trace!("put", T);
+        let type_id = TypeId::of::<T>();
+        self.check_frozen(type_id);
+        self.expire(type_id);
+        self.insert_meta(type_id, Meta::of::<T>());
+        let mut old = None;
+        let slot = match self.data.entry(type_id) {
+            btree_map::Entry::Occupied(e) => {
+                let b = e.into_mut();
+                match b.downcast_mut::<T>() {
+                    Some(existing) => old = Some(std::mem::replace(existing, value)),
+                    None => *b = Box::new(value),
+                }
+                b
+            }
+            btree_map::Entry::Vacant(e) => e.insert(Box::new(value)),
+        };
+        if let Some(observer) = self.observers.get(&type_id) {
+            observer(slot.as_ref());
+        }
+        self.watchers.retain(|w| w.send(type_id).is_ok());
+        (slot.downcast_mut().unwrap(), old)
+    }
+
+    /// Splits the `GothamStore` into its values of one per type and the
+    /// [`KeyedStore`] holding the values put under a key.
+    pub fn into_parts(mut self) -> (GothamStore, KeyedStore) {
+        let keyed = std::mem::take(&mut self.keyed);
+        (self, keyed)
+    }
+
+    /// Converts a [`KeyedStore`] holding at most one value of each type into
+    /// a `GothamStore`, dropping the keys.
+    ///
+    /// If some type is stored under more than one key, the `KeyedStore` is
+    /// returned unchanged. Like values inserted via
+    /// [`insert_any`](Self::insert_any), the values have no captured type
+    /// name.
+    pub fn flatten_keyed(keyed: KeyedStore) -> Result<GothamStore, KeyedStore> {
+        // The keys are sorted by TypeId first, so duplicates are adjacent.
+        let ids: Vec<_> = keyed.data.keys().map(|(id, _)| *id).collect();
+        if ids.windows(2).any(|w| w[0] == w[1]) {
+            return Err(keyed);
+        }
+        let mut store = GothamStore::default();
+        for ((id, _), b) in keyed.data {
+            store.insert_any(id, b);
+        }
+        Ok(store)
+    }
+
+    /// Registers a lens under the given id, which projects the value of type
+    /// `T` onto a `U`, e.g. one of its fields. Successive calls to
+    /// `register_lens` will overwrite the existing lens of the same id.
+    ///
+    /// The lens can then be borrowed through via
+    /// [`borrow_lens`](Self::borrow_lens) without naming `T`.
+    pub fn register_lens<T: 'static, U: 'static>(&mut self, id: &'static str, f: fn(&T) -> &U) {
+        let lens: Box<Lens> =
+            Box::new(move |store| store.try_borrow::<T>().map(|t| f(t) as &dyn Any));
+        self.lenses.insert(id, lens);
+    }
+
+    /// Tries to borrow a value through the lens registered under the given
+    /// id, returning `None` if there is no such lens, if it does not project
+    /// onto a `U`, or if the value it projects from is missing.
+    pub fn borrow_lens<U: 'static>(&self, id: &str) -> Option<&U> {
+        let lens = self.lenses.get(id)?;
+        lens(self)?.downcast_ref()
+    }
+
+    /// Rebuilds the map backing the `GothamStore` from scratch.
+    ///
+    /// This can improve the memory locality of a long-lived store that has
+    /// seen many insertions and removals, at the cost of moving every entry
+    /// once. The values themselves stay in place.
+    pub fn defragment(&mut self) {
+        self.data = std::mem::take(&mut self.data).into_iter().collect();
+    }
+
+    /// Removes every value from the `GothamStore` and returns it together with
+    /// its captured type name, in `TypeId` order.
+    ///
+    /// Values without a captured type name are returned under the name
+    /// `"<unnamed>"`. Values of [frozen](Self::freeze_type) types are left in
+    /// place.
+    pub fn drain_named(&mut self) -> Vec<(&'static str, Box<dyn Any>)> {
+        self.expire_all();
+        let ids: Vec<TypeId> = self
+            .data
+            .keys()
+            .filter(|id| !self.is_locked(id))
+            .copied()
+            .collect();
+        ids.into_iter()
+            .filter_map(|id| self.remove_entry(id))
+            .map(|(b, meta)| (meta.name.unwrap_or("<unnamed>"), b))
+            .collect()
+    }
+
+    /// Puts a value into the [`KeyedStore`] at the given index, e.g. for
+    /// per-worker state.
+    pub fn put_indexed<T: 'static>(&mut self, index: usize, value: T) {
+        self.check_sealed();
+        self.keyed.put_indexed(index, value);
+    }
+
+    /// Tries to borrow the value of type `T` stored at the given index.
+    pub fn get_indexed<T: 'static>(&self, index: usize) -> Option<&T> {
+        self.keyed.get_indexed(index)
+    }
+
+    /// Returns the number of values of type `T` stored at an index.
+    pub fn len_indexed<T: 'static>(&self) -> usize {
+        self.keyed.len_indexed::<T>()
+    }
+
+    /// Tries to borrow a value of type `T` from a `GothamStore` nested in the
+    /// value of type `Outer`, as located by `extract`.
+    pub fn borrow_nested<Outer: 'static, T: 'static>(
+        &self,
+        extract: fn(&Outer) -> &GothamStore,
+    ) -> Option<&T> {
+        extract(self.try_borrow()?).try_borrow()
+    }
+}
+
+/// A view into a single type's slot in a `GothamStore`, which may either be
+/// vacant or occupied.
+///
+/// This is constructed from the [`entry`](GothamStore::entry) method.
+pub enum Entry<'a, T: 'static> {
+    /// A value of type `T` is present.
+    Occupied(OccupiedEntry<'a, T>),
+    /// No value of type `T` is present.
+    Vacant(VacantEntry<'a, T>),
+}
+
+impl<'a, T: 'static> Entry<'a, T> {
+    /// Ensures a value is present by inserting `default` if empty, and returns
+    /// a mutable reference to the value.
+    pub fn or_insert(self, default: T) -> &'a mut T {
+        self.or_insert_with(|| default)
+    }
+
+    /// Ensures a value is present by inserting the result of `default` if
+    /// empty, and returns a mutable reference to the value.
+    pub fn or_insert_with(self, default: impl FnOnce() -> T) -> &'a mut T {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(default()),
+        }
+    }
+
+    /// Ensures a value is present by inserting `T::default()` if empty, and
+    /// returns a mutable reference to the value.
+    pub fn or_default(self) -> &'a mut T
+    where
+        T: Default,
+    {
+        self.or_insert_with(T::default)
+    }
+}
+
+/// A view into an occupied entry in a `GothamStore`. It is part of the
+/// [`Entry`] enum.
+pub struct OccupiedEntry<'a, T: 'static> {
+    store: &'a mut GothamStore,
+    marker: PhantomData<T>,
+}
+
+impl<'a, T: 'static> OccupiedEntry<'a, T> {
+    /// Gets a reference to the value in the entry.
+    pub fn get(&self) -> &T {
+        self.store.borrow()
+    }
+
+    /// Gets a mutable reference to the value in the entry.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.store.borrow_mut()
+    }
+
+    /// Converts the entry into a mutable reference to its value.
+    pub fn into_mut(self) -> &'a mut T {
+        self.store.borrow_mut()
+    }
+
+    /// Sets the value of the entry, and returns the entry's old value.
+    pub fn insert(&mut self, value: T) -> T {
+        let old = self.store.take();
+        self.store.put(value);
+        old
+    }
+
+    /// Takes the value out of the entry, and returns it.
+    pub fn remove(self) -> T {
+        self.store.take()
+    }
+}
+
+/// A view into a vacant entry in a `GothamStore`. It is part of the [`Entry`]
+/// enum.
+pub struct VacantEntry<'a, T: 'static> {
+    store: &'a mut GothamStore,
+    marker: PhantomData<T>,
+}
+
+impl<'a, T: 'static> VacantEntry<'a, T> {
+    /// Sets the value of the entry, and returns a mutable reference to it.
+    pub fn insert(self, value: T) -> &'a mut T {
+        self.store.put(value);
+        self.store.borrow_mut()
+    }
+}
+
+/// The result of [`borrow_either`](GothamStore::borrow_either).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OneOf<A, B> {
+    /// A value of the first type is present.
+    A(A),
+    /// Only a value of the second type is present.
+    B(B),
+    /// Neither value is present.
+    Neither,
+}
+
+/// An error returned by the fallible `GothamStore` operations.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum StoreError {
+    /// A JSON value could not be deserialized into the requested type.
+    #[cfg(feature = "serde")]
+    Json(serde_json::Error),
+    /// A `TypeId` did not match the type of the value it was given for.
+    TypeMismatch,
+    /// The same type was requested twice where distinct types are required.
+    SameType,
+    /// No value of the named type is present.
+    Missing(&'static str),
+    /// An entry holds a value whose type does not match its `TypeId` key.
+    Inconsistent {
+        /// The `TypeId` the value is stored under.
+        key: TypeId,
+        /// The `TypeId` of the value itself.
+        actual: TypeId,
+    },
+    /// The named type is [frozen](GothamStore::freeze_type).
+    Frozen(&'static str),
+    /// The `GothamStore` is [sealed](GothamStore::seal).
+    Sealed,
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            #[cfg(feature = "serde")]
+            StoreError::Json(err) => write!(f, "failed to deserialize JSON value: {}", err),
+            StoreError::TypeMismatch => f.write_str("TypeId does not match the type of the value"),
+            StoreError::SameType => f.write_str("the same type was requested twice"),
+            StoreError::Missing(name) => write!(
+                f,
+                "required type {} is not present in GothamStore container",
+                name
+            ),
+            StoreError::Inconsistent { key, actual } => {
+                write!(f, "entry for {:?} holds a value of type {:?}", key, actual)
+            }
+            StoreError::Frozen(name) => {
+                write!(f, "type {} is frozen in GothamStore container", name)
+            }
+            StoreError::Sealed => f.write_str("GothamStore container is sealed"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "serde")]
+            StoreError::Json(err) => Some(err),
+            StoreError::TypeMismatch
+            | StoreError::SameType
+            | StoreError::Missing(_)
+            | StoreError::Inconsistent { .. }
+            | StoreError::Frozen(_)
+            | StoreError::Sealed => None,
+        }
+    }
+}
+
+/// A read-only view of a `GothamStore`, which only allows borrowing values and
+/// inspecting which types are present.
+///
+/// This is constructed from the [`readonly`](GothamStore::readonly) method.
+#[derive(Clone, Copy, Debug)]
+pub struct ReadOnlyStore<'a>(&'a GothamStore);
+
+impl<'a> ReadOnlyStore<'a> {
+    /// Tries to borrow a value from the underlying `GothamStore`.
+    pub fn try_borrow<T: 'static>(&self) -> Option<&'a T> {
+        self.0.try_borrow()
+    }
+
+    /// Determines if the current value exists in the underlying
+    /// `GothamStore`.
+    pub fn has<T: 'static>(&self) -> bool {
+        self.0.has::<T>()
+    }
+
+    /// Returns the captured type names of the values in the underlying
+    /// `GothamStore`.
+    pub fn type_names(&self) -> Vec<&'static str> {
+        self.0.type_names()
+    }
+}
+
+/// A container holding values of any Rust type under user-chosen string tags.
+///
+/// Unlike `TypeId`s, tags are stable across builds, which makes them suitable
+/// as keys for persisting values.
+#[derive(Default, Debug)]
+pub struct TaggedStore {
+    data: BTreeMap<&'static str, Box<dyn Any>>,
+}
+
+impl TaggedStore {
+    /// Puts a value into the `TaggedStore` under the given tag. Successive
+    /// calls to `put_tagged` will overwrite the existing value of the same
+    /// tag, regardless of its type.
+    pub fn put_tagged<T: 'static>(&mut self, tag: &'static str, value: T) {
+        self.data.insert(tag, Box::new(value));
+    }
+
+    /// Tries to borrow the value stored under the given tag, returning `None`
+    /// if there is no such value or if it is not of type `T`.
+    pub fn get_tagged<T: 'static>(&self, tag: &str) -> Option<&T> {
+        self.data.get(tag).and_then(|b| b.downcast_ref())
+    }
+}
+
+/// A type that knows how to construct its own default value for a
+/// `GothamStore`, e.g. from the environment.
+///
+/// This is used by [`borrow_or_store_default`](GothamStore::borrow_or_store_default).
+pub trait StoreDefault {
+    /// Returns the value to put into the `GothamStore` when none is present.
+    fn store_default() -> Self;
+}
+
+/// A callback invoked with every value put into a `GothamStore`.
+type Observer = dyn Fn(&dyn Any);
+
+/// Builds the panic message for a missing type from its name.
+type MissingFormatter = dyn Fn(&'static str) -> String;
+
+/// Converts a boxed value recorded via `put_sync` into its `Send + Sync` form.
+type ToSync = fn(Box<dyn Any>) -> Box<dyn Any + Send + Sync>;
+
+/// Feeds a boxed value recorded via `put_hashable` into a hasher.
+type HashValue = fn(&dyn Any, &mut DefaultHasher);
+
+/// Projects a value in a `GothamStore` as registered via `register_lens`.
+type Lens = dyn Fn(&GothamStore) -> Option<&dyn Any>;
+
+/// Clones a boxed default recorded via `register_global_default`.
+type CloneValue = fn(&dyn Any) -> Box<dyn Any>;
+
+/// Checks if a boxed `Weak` recorded via `put_weak` still has a referent.
+type IsLive = fn(&dyn Any) -> bool;
+
+/// Bookkeeping kept alongside each value in a `GothamStore`.
+#[derive(Clone, Debug, Default)]
+struct Meta {
+    name: Option<&'static str>,
+    to_sync: Option<ToSync>,
+    hash: Option<HashValue>,
+    is_live: Option<IsLive>,
+    drop_order: i32,
+    expires: Option<Instant>,
+    #[cfg(feature = "timestamps")]
+    inserted: Option<Instant>,
+}
+
+impl Meta {
+    fn of<T: 'static>() -> Self {
+        Meta {
+            name: Some(type_name::<T>()),
+            to_sync: None,
+            hash: None,
+            is_live: None,
+            drop_order: 0,
+            expires: None,
+            #[cfg(feature = "timestamps")]
+            inserted: None,
+        }
+    }
+}
+
+/// A container holding at most one value of any `Send + Sync` Rust type, which
+/// can be shared between threads.
+///
+/// This is usually obtained from [`GothamStore::into_sync`].
+#[derive(Default, Debug)]
+pub struct SyncGothamStore {
+    data: BTreeMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl SyncGothamStore {
+    /// Puts a value into the `SyncGothamStore`. One value of each type is
+    /// retained. Successive calls to `put` will overwrite the existing value
+    /// of the same type.
+    pub fn put<T: Send + Sync + 'static>(&mut self, t: T) {
+        self.data.insert(TypeId::of::<T>(), Box::new(t));
+    }
+
+    /// Determines if the current value exists in `SyncGothamStore`.
+    pub fn has<T: 'static>(&self) -> bool {
+        self.data.contains_key(&TypeId::of::<T>())
+    }
+
+    /// Tries to borrow a value from the `SyncGothamStore`.
+    pub fn try_borrow<T: 'static>(&self) -> Option<&T> {
+        self.data
+            .get(&TypeId::of::<T>())
+            .and_then(|b| b.downcast_ref())
+    }
+
+    /// Borrows a value from the `SyncGothamStore`.
+    pub fn borrow<T: 'static>(&self) -> &T {
+        self.try_borrow().unwrap_or_else(|| missing::<T>())
+    }
+
+    /// Tries to mutably borrow a value from the `SyncGothamStore`.
+    pub fn try_borrow_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.data
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|b| b.downcast_mut())
+    }
+
+    /// Mutably borrows a value from the `SyncGothamStore`.
+    pub fn borrow_mut<T: 'static>(&mut self) -> &mut T {
+        self.try_borrow_mut().unwrap_or_else(|| missing::<T>())
+    }
+
+    /// Tries to move a value out of the `SyncGothamStore` and return
+    /// ownership.
+    pub fn try_take<T: 'static>(&mut self) -> Option<T> {
+        self.data
+            .remove(&TypeId::of::<T>())
+            .and_then(|b| b.downcast().ok())
+            .map(|b| *b)
+    }
+
+    /// Moves a value out of the `SyncGothamStore` and returns ownership.
+    ///
+    /// # Panics
+    ///
+    /// If a value of type `T` is not present in `SyncGothamStore`.
+    pub fn take<T: 'static>(&mut self) -> T {
+        self.try_take().unwrap_or_else(|| missing::<T>())
+    }
+
+    /// Returns the number of values in the `SyncGothamStore`.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns `true` if the `SyncGothamStore` contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// A value taken out of a `GothamStore` that is put back when the guard is
+/// dropped, unless it is [committed](Self::commit).
+///
+/// This is constructed from the [`take_guard`](GothamStore::take_guard) method.
+pub struct TakeGuard<'a, T: 'static> {
+    store: &'a mut GothamStore,
+    taken: Option<(Box<T>, Meta)>,
+}
+
+impl<T: 'static> TakeGuard<'_, T> {
+    /// Keeps the value out of the `GothamStore` and returns ownership.
+    pub fn commit(mut self) -> T {
+        let (value, _) = self.taken.take().unwrap();
+        *value
+    }
+}
+
+impl<T: 'static> std::ops::Deref for TakeGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.taken.as_ref().unwrap().0
+    }
+}
+
+impl<T: 'static> std::ops::DerefMut for TakeGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.taken.as_mut().unwrap().0
+    }
+}
+
+impl<T: 'static> Drop for TakeGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some((value, meta)) = self.taken.take() {
+            self.store.insert_entry(TypeId::of::<T>(), value, meta);
+        }
+    }
+}
+
+/// Borrows several values from a `GothamStore` into local bindings.
+///
+/// ```
+/// # use gotham_store::{require, GothamStore};
+/// let mut store = GothamStore::default();
+/// store.put(42u32);
+/// store.put("localhost".to_string());
+/// require!(store => port: u32, host: String);
+/// assert_eq!(*port, 42);
+/// assert_eq!(host, "localhost");
+/// ```
+///
+/// # Panics
+///
+/// If a value of any of the given types is not present in `GothamStore`. The
+/// panic message names the binding that could not be satisfied.
+#[macro_export]
+macro_rules! require {
+    ($store:expr => $($name:ident: $ty:ty),+ $(,)?) => {
+        let store = &$store;
+        $(
+            let $name = store.try_borrow::<$ty>().unwrap_or_else(|| {
+                panic!(
+                    "required binding `{}` of type {} is not present in GothamStore container",
+                    stringify!($name),
+                    ::std::any::type_name::<$ty>()
+                )
+            });
+        )+
+    };
+}
+
+/// A builder for registering typed observers on a `GothamStore`.
+///
+/// This is constructed from the [`observers`](GothamStore::observers) method.
+pub struct Observers<'a> {
+    store: &'a mut GothamStore,
+}
+
+impl Observers<'_> {
+    /// Registers `f` to be invoked with every value of type `T` that is put
+    /// into the `GothamStore`, replacing any observer previously registered
+    /// for `T`.
+    pub fn on<T: 'static>(self, f: impl Fn(&T) + 'static) -> Self {
+        self.store.observers.insert(
+            TypeId::of::<T>(),
+            Box::new(move |value| {
+                if let Some(t) = value.downcast_ref() {
+                    f(t)
+                }
+            }),
+        );
+        self
+    }
+}
+
+/// Returns the `TypeId`s present in `after` but not in `before`, in `TypeId`
+/// order.
+///
+/// The snapshots are usually obtained from
+/// [`type_id_snapshot`](GothamStore::type_id_snapshot).
+pub fn added_between(before: &BTreeSet<TypeId>, after: &BTreeSet<TypeId>) -> Vec<TypeId> {
+    after.difference(before).copied().collect()
+}
+
+/// A type that can be loaded from the environment into a `GothamStore`.
+///
+/// This is used by [`load_env`](GothamStore::load_env).
+pub trait FromEnv: Sized {
+    /// Loads the value from the environment, returning `None` if it is not
+    /// configured.
+    fn from_env() -> Option<Self>;
+}
+
+/// Aggregate metadata about a `GothamStore`.
+///
+/// This is returned by the [`stats`](GothamStore::stats) method.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// The number of values, as returned by [`len`](GothamStore::len).
+    pub entry_count: usize,
+    /// The total size of all values, as summed from
+    /// [`entry_sizes`](GothamStore::entry_sizes).
+    pub total_value_bytes: usize,
+    /// The captured type names, as returned by
+    /// [`type_names`](GothamStore::type_names).
+    pub type_names: Vec<&'static str>,
+}
+
+/// The read side of a store keyed by type, for writing code that is generic
+/// over `GothamStore` and its variants.
+///
+/// The methods are generic over the value type, so this trait is not object
+/// safe and is meant for static dispatch, e.g. `&impl TypeStore`.
+pub trait TypeStore {
+    /// Tries to borrow a value from the store.
+    fn try_borrow<T: 'static>(&self) -> Option<&T>;
+
+    /// Determines if the current value exists in the store.
+    fn has<T: 'static>(&self) -> bool {
+        self.try_borrow::<T>().is_some()
+    }
+}
+
+/// The mutating side of a store keyed by type.
+///
+/// Like [`TypeStore`], this trait is meant for static dispatch only.
+pub trait TypeStoreMut: TypeStore {
+    /// Puts a value into the store.
+    fn put<T: 'static>(&mut self, t: T);
+
+    /// Tries to mutably borrow a value from the store.
+    fn try_borrow_mut<T: 'static>(&mut self) -> Option<&mut T>;
+
+    /// Tries to move a value out of the store and return ownership.
+    fn try_take<T: 'static>(&mut self) -> Option<T>;
+}
+
+impl TypeStore for GothamStore {
+    fn try_borrow<T: 'static>(&self) -> Option<&T> {
+        GothamStore::try_borrow(self)
+    }
+
+    fn has<T: 'static>(&self) -> bool {
+        GothamStore::has::<T>(self)
+    }
+}
+
+impl TypeStoreMut for GothamStore {
+    fn put<T: 'static>(&mut self, t: T) {
+        GothamStore::put(self, t)
+    }
+
+    fn try_borrow_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        GothamStore::try_borrow_mut(self)
+    }
+
+    fn try_take<T: 'static>(&mut self) -> Option<T> {
+        GothamStore::try_take(self)
+    }
+}
+
+impl TypeStore for ReadOnlyStore<'_> {
+    fn try_borrow<T: 'static>(&self) -> Option<&T> {
+        ReadOnlyStore::try_borrow(self)
+    }
+}
+
+impl TypeStore for SyncGothamStore {
+    fn try_borrow<T: 'static>(&self) -> Option<&T> {
+        SyncGothamStore::try_borrow(self)
+    }
+}
+
+/// A type that can construct itself from other values in a `GothamStore`.
+///
+/// This is used by [`resolve`](GothamStore::resolve), which ensures that the
+/// values listed in `Deps` are present before calling `provide`.
+pub trait Provide: Sized {
+    /// The types this type is constructed from, as a tuple of up to four
+    /// `Provide` types, or `()` if there are none.
+    type Deps: Dependencies;
+
+    /// Constructs the value from its dependencies in the `GothamStore`.
+    fn provide(store: &GothamStore) -> Self;
+}
+
+/// A set of [`Provide`] types that can be resolved together.
+///
+/// This is implemented for `()` and tuples of up to four `Provide` types.
+pub trait Dependencies {
+    /// Resolves each of the types in the `GothamStore`.
+    fn resolve_all(store: &mut GothamStore);
+}
+
+macro_rules! impl_dependencies {
+    ($($t:ident),*) => {
+        impl<$($t: Provide + 'static),*> Dependencies for ($($t,)*) {
+            #[allow(unused_variables)]
+            fn resolve_all(store: &mut GothamStore) {
+                $(store.resolve::<$t>();)*
+            }
+        }
+    };
+}
+
+impl_dependencies!();
+impl_dependencies!(A);
+impl_dependencies!(A, B);
+impl_dependencies!(A, B, C);
+impl_dependencies!(A, B, C, D);
+
+/// A container holding any number of values of each Rust type, addressed by
+/// the type together with a string key or a numeric index.
+///
+/// Every `GothamStore` has one, reachable via
+/// [`keyed`](GothamStore::keyed), the `keyed_*` methods and the `*_indexed`
+/// methods.
+#[derive(Default, Debug)]
+pub struct KeyedStore {
+    data: BTreeMap<(TypeId, Key), Box<dyn Any>>,
+}
+
+/// The key of a value in a `KeyedStore`, next to its `TypeId`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Name(String),
+    Index(usize),
+}
+
+impl KeyedStore {
+    /// Puts a value into the `KeyedStore` under the given key. Successive
+    /// calls to `put_keyed` will overwrite the existing value of the same type
+    /// and key.
+    pub fn put_keyed<T: 'static>(&mut self, key: impl Into<String>, value: T) {
+        self.data
+            .insert((TypeId::of::<T>(), Key::Name(key.into())), Box::new(value));
+    }
+
+    /// Tries to borrow the value of type `T` stored under the given key.
+    pub fn get_keyed<T: 'static>(&self, key: &str) -> Option<&T> {
+        self.data
+            .get(&(TypeId::of::<T>(), Key::Name(key.to_string())))
+            .and_then(|b| b.downcast_ref())
+    }
+
+    /// Mutably borrows the value of type `T` stored under the given key,
+    /// putting the result of `f` there first if it is missing.
+    pub fn get_or_insert_with<T: 'static>(
+        &mut self,
+        key: impl Into<String>,
+        f: impl FnOnce() -> T,
+    ) -> &mut T {
+        self.data
+            .entry((TypeId::of::<T>(), Key::Name(key.into())))
+            .or_insert_with(|| Box::new(f()))
+            .downcast_mut()
+            .unwrap()
+    }
+
+    /// Puts a value into the `KeyedStore` at the given index. Successive
+    /// calls to `put_indexed` will overwrite the existing value of the same
+    /// type and index.
+    pub fn put_indexed<T: 'static>(&mut self, index: usize, value: T) {
+        self.data
+            .insert((TypeId::of::<T>(), Key::Index(index)), Box::new(value));
+    }
+
+    /// Tries to borrow the value of type `T` stored at the given index.
+    pub fn get_indexed<T: 'static>(&self, index: usize) -> Option<&T> {
+        self.data
+            .get(&(TypeId::of::<T>(), Key::Index(index)))
+            .and_then(|b| b.downcast_ref())
+    }
+
+    /// Returns the number of values of type `T` stored at an index.
+    pub fn len_indexed<T: 'static>(&self) -> usize {
+        let type_id = TypeId::of::<T>();
+        self.data
+            .range((type_id, Key::Index(0))..=(type_id, Key::Index(usize::MAX)))
+            .count()
+    }
+
+    /// Returns the number of values in the `KeyedStore`.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns `true` if the `KeyedStore` contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// A [`SyncGothamStore`] snapshot that can be read without locking and
+/// replaced atomically as a whole, e.g. for configuration that is rebuilt on
+/// reload.
+///
+/// Writers build a new `SyncGothamStore` and swap it in via
+/// [`store`](Self::store); readers keep seeing the snapshot they loaded.
+#[cfg(feature = "arc-swap")]
+#[derive(Debug, Default)]
+pub struct AtomicGothamStore {
+    inner: arc_swap::ArcSwap<SyncGothamStore>,
+}
+
+#[cfg(feature = "arc-swap")]
+impl AtomicGothamStore {
+    /// Creates an `AtomicGothamStore` holding the given snapshot.
+    pub fn new(store: SyncGothamStore) -> Self {
+        AtomicGothamStore {
+            inner: arc_swap::ArcSwap::from_pointee(store),
+        }
+    }
+
+    /// Loads the current snapshot.
+    pub fn load(&self) -> std::sync::Arc<SyncGothamStore> {
+        self.inner.load_full()
+    }
+
+    /// Replaces the current snapshot with `store`.
+    pub fn store(&self, store: SyncGothamStore) {
+        self.inner.store(std::sync::Arc::new(store));
+    }
+}
+
+/// A stack of `GothamStore`s that is read from the first store holding a
+/// value of the requested type, e.g. for request, session and global scopes.
+///
+/// This is created by [`chain`].
+#[derive(Clone, Debug)]
+pub struct StoreChain<'a> {
+    stores: Vec<&'a GothamStore>,
+}
+
+impl<'a> StoreChain<'a> {
+    /// Tries to borrow a value from the first store that holds one.
+    pub fn try_borrow<T: 'static>(&self) -> Option<&'a T> {
+        self.stores.iter().find_map(|store| store.try_borrow())
+    }
+
+    /// Determines if any of the stores holds a value of type `T`.
+    pub fn has<T: 'static>(&self) -> bool {
+        self.stores.iter().any(|store| store.has::<T>())
+    }
+}
+
+impl TypeStore for StoreChain<'_> {
+    fn try_borrow<T: 'static>(&self) -> Option<&T> {
+        StoreChain::try_borrow(self)
+    }
+
+    fn has<T: 'static>(&self) -> bool {
+        StoreChain::has::<T>(self)
+    }
+}
+
+/// Creates a [`StoreChain`] from the given stores, ordered from the innermost
+/// scope to the root.
+pub fn chain<'a>(stores: &[&'a GothamStore]) -> StoreChain<'a> {
+    StoreChain {
+        stores: stores.to_vec(),
+    }
+}
+
+/// A closure that contributes values to a `GothamStore`, as used by
+/// [`from_providers`](GothamStore::from_providers).
+pub type Provider = Box<dyn FnOnce(&mut GothamStore)>;
+
+/// Unwinds the stack of types being resolved by `GothamStore::resolve`, even
+/// if a provider panics.
+struct ResolvingGuard<'a> {
+    store: &'a mut GothamStore,
+    len: usize,
+}
+
+impl Drop for ResolvingGuard<'_> {
+    fn drop(&mut self) {
+        self.store.resolving.truncate(self.len);
+    }
+}
+
+fn missing<T: 'static>() -> ! {
+    panic!(
+        "required type {} is not present in GothamStore container",
+        type_name::<T>()
+    );
+}
+
+fn missing_with<T: 'static>(formatter: Option<&MissingFormatter>) -> ! {
+    match formatter {
+        Some(f) => panic!("{}", f(type_name::<T>())),
+        None => missing::<T>(),
+    }
+}
+
+fn missing_ctx<T: 'static>(context: &str) -> ! {
+    panic!(
+        "{}: required type {} is not present in GothamStore container",
+        context,
+        type_name::<T>()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        added_between, chain, Entry, FromEnv, GothamStore, KeyedStore, OneOf, Provide, Provider,
+        StoreDefault, StoreError, TaggedStore, TypeStore, TypeStoreMut,
+    };
+    use std::any::TypeId;
+    use std::rc::Rc;
+
+    struct MyStruct {
+        value: i32,
+    }
+
+    struct AnotherStruct {
+        value: &'static str,
+    }
+
+    type Alias1 = String;
+    type Alias2 = String;
+
+    #[test]
+    fn put_borrow1() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 1 });
+        assert_eq!(store.borrow::<MyStruct>().value, 1);
+    }
+
+    #[test]
+    fn put_borrow2() {
+        let mut store = GothamStore::default();
+        assert!(!store.has::<AnotherStruct>());
+        store.put(AnotherStruct { value: "a string" });
+        assert!(store.has::<AnotherStruct>());
+        assert!(!store.has::<MyStruct>());
+        store.put(MyStruct { value: 100 });
+        assert!(store.has::<MyStruct>());
+        assert_eq!(store.borrow::<MyStruct>().value, 100);
+        assert_eq!(store.borrow::<AnotherStruct>().value, "a string");
+    }
+
+    #[test]
+    fn insert_any() {
+        let mut store = GothamStore::default();
+        let id = TypeId::of::<MyStruct>();
+        assert!(store
+            .insert_any(id, Box::new(MyStruct { value: 1 }))
+            .is_none());
+        assert_eq!(store.borrow::<MyStruct>().value, 1);
+        let old = store
+            .insert_any(id, Box::new(MyStruct { value: 2 }))
+            .unwrap();
+        assert_eq!(old.downcast_ref::<MyStruct>().unwrap().value, 1);
+        assert_eq!(store.borrow::<MyStruct>().value, 2);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "TypeId does not match the type of the boxed value")]
+    fn insert_any_mismatch() {
+        let mut store = GothamStore::default();
+        store.insert_any(
+            TypeId::of::<MyStruct>(),
+            Box::new(AnotherStruct { value: "a" }),
+        );
+    }
+
+    #[test]
+    fn try_borrow() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        assert!(store.try_borrow::<MyStruct>().is_some());
+        assert_eq!(store.try_borrow::<MyStruct>().unwrap().value, 100);
+        assert!(store.try_borrow::<AnotherStruct>().is_none());
+    }
+
+    #[test]
+    fn view() {
+        let mut store = GothamStore::default();
+        assert!(store.view(|s: &AnotherStruct| s.value).is_none());
+        store.put(AnotherStruct { value: "a string" });
+        assert_eq!(store.view(|s: &AnotherStruct| s.value), Some("a string"));
+    }
+
+    #[test]
+    fn try_borrow_mut() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        if let Some(a) = store.try_borrow_mut::<MyStruct>() {
+            a.value += 10;
+        }
+        assert_eq!(store.borrow::<MyStruct>().value, 110);
+    }
+
+    #[test]
+    fn borrow_mut() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        {
+            let a = store.borrow_mut::<MyStruct>();
+            a.value += 10;
+        }
+        assert_eq!(store.borrow::<MyStruct>().value, 110);
+        assert!(store.try_borrow_mut::<AnotherStruct>().is_none());
+    }
+
+    #[test]
+    fn try_take() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        assert_eq!(store.try_take::<MyStruct>().unwrap().value, 100);
+        assert!(store.try_take::<MyStruct>().is_none());
+        assert!(store.try_borrow_mut::<MyStruct>().is_none());
+        assert!(store.try_borrow::<MyStruct>().is_none());
+        assert!(store.try_take::<AnotherStruct>().is_none());
+    }
+
+    #[test]
+    fn take() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 110 });
+        assert_eq!(store.take::<MyStruct>().value, 110);
+        assert!(store.try_take::<MyStruct>().is_none());
+        assert!(store.try_borrow_mut::<MyStruct>().is_none());
+        assert!(store.try_borrow::<MyStruct>().is_none());
+    }
+
+    #[test]
+    fn type_alias() {
+        let mut store = GothamStore::default();
+        store.put::<Alias1>("alias1".to_string());
+        store.put::<Alias2>("alias2".to_string());
+        assert_eq!(store.take::<Alias1>(), "alias2");
+        assert!(store.try_take::<Alias1>().is_none());
+        assert!(store.try_take::<Alias2>().is_none());
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "required type gotham_store::tests::MyStruct is not present in GothamStore container"
+    )]
+    fn missing() {
+        let store = GothamStore::default();
+        let _ = store.borrow::<MyStruct>();
+    }
+
+    #[test]
+    fn deref() {
+        let mut store = GothamStore::default();
+        assert!((*store).is_empty());
+        assert_eq!((*store).len(), 0);
+        store.put(MyStruct { value: 100 });
+        assert!(!(*store).is_empty());
+        assert_eq!((*store).len(), 1);
+        store.take::<MyStruct>();
+        assert!((*store).is_empty());
+        assert_eq!((*store).len(), 0);
+    }
+
+    #[test]
+    fn count_is_empty() {
+        let mut store = GothamStore::default();
+        assert!(store.is_empty());
+        assert_eq!(store.count(), 0);
+        assert_eq!(store.is_empty(), (*store).is_empty());
+        store.put(MyStruct { value: 100 });
+        store.put(AnotherStruct { value: "a string" });
+        assert!(!store.is_empty());
+        assert_eq!(store.count(), 2);
+        assert_eq!(store.count(), (*store).len());
+        assert_eq!(store.is_empty(), (*store).is_empty());
+        store.take::<MyStruct>();
+        assert_eq!(store.count(), 1);
+        assert_eq!(store.count(), (*store).len());
+        store.take::<AnotherStruct>();
+        assert!(store.is_empty());
+        assert_eq!(store.count(), (*store).len());
+        assert_eq!(store.is_empty(), (*store).is_empty());
+    }
+
+    #[test]
+    fn with_vec_mut() {
+        let mut store = GothamStore::default();
+        assert!(store.with_vec_mut(|v: &mut Vec<i32>| v.len()).is_none());
+        store.put(vec![1, 2, 3, 4]);
+        let sum = store.with_vec_mut(|v: &mut Vec<i32>| {
+            let (left, right) = v.split_at_mut(2);
+            std::mem::swap(&mut left[0], &mut right[1]);
+            left[0] + right[1]
+        });
+        assert_eq!(sum, Some(5));
+        assert_eq!(store.borrow::<Vec<i32>>(), &[4, 2, 3, 1]);
+    }
+
+    #[test]
+    fn entry() {
+        let mut store = GothamStore::default();
+        match store.entry::<MyStruct>() {
+            Entry::Occupied(_) => panic!("expected vacant entry"),
+            Entry::Vacant(e) => e.insert(MyStruct { value: 1 }).value += 1,
+        }
+        assert_eq!(store.borrow::<MyStruct>().value, 2);
+
+        match store.entry::<MyStruct>() {
+            Entry::Occupied(mut e) => {
+                assert_eq!(e.get().value, 2);
+                e.get_mut().value += 1;
+                assert_eq!(e.insert(MyStruct { value: 10 }).value, 3);
+                assert_eq!(e.get().value, 10);
+            }
+            Entry::Vacant(_) => panic!("expected occupied entry"),
+        }
+        assert_eq!(store.borrow::<MyStruct>().value, 10);
+
+        match store.entry::<MyStruct>() {
+            Entry::Occupied(e) => assert_eq!(e.remove().value, 10),
+            Entry::Vacant(_) => panic!("expected occupied entry"),
+        }
+        assert!(!store.has::<MyStruct>());
+    }
+
+    #[test]
+    fn entry_or_insert() {
+        let mut store = GothamStore::default();
+        store.entry().or_insert(MyStruct { value: 1 }).value += 1;
+        store.entry().or_insert(MyStruct { value: 100 }).value += 1;
+        assert_eq!(store.borrow::<MyStruct>().value, 3);
+        assert_eq!(*store.entry::<u32>().or_default(), 0);
+        assert_eq!(
+            store
+                .entry()
+                .or_insert_with(|| AnotherStruct { value: "a string" })
+                .value,
+            "a string"
+        );
+    }
+
+    #[test]
+    fn move_type_into() {
+        let mut src = GothamStore::default();
+        let mut dest = GothamStore::default();
+        src.put(MyStruct { value: 100 });
+        src.put(AnotherStruct { value: "a string" });
+        assert!(src.move_type_into::<MyStruct>(&mut dest));
+        assert!(!src.has::<MyStruct>());
+        assert!(src.has::<AnotherStruct>());
+        assert_eq!(dest.borrow::<MyStruct>().value, 100);
+        assert!(!src.move_type_into::<MyStruct>(&mut dest));
+        assert_eq!(dest.len(), 1);
+    }
+
+    #[test]
+    fn assert_present_absent() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store.put(AnotherStruct { value: "a string" });
+        store
+            .assert_present::<MyStruct>()
+            .assert_present::<AnotherStruct>()
+            .assert_absent::<u32>();
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "required type gotham_store::tests::AnotherStruct is not present in GothamStore container"
+    )]
+    fn assert_present_missing() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store
+            .assert_present::<MyStruct>()
+            .assert_present::<AnotherStruct>();
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "unexpected type gotham_store::tests::MyStruct is present in GothamStore container"
+    )]
+    fn assert_absent_present() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store
+            .assert_absent::<AnotherStruct>()
+            .assert_absent::<MyStruct>();
+    }
+
+    #[test]
+    fn borrow_either() {
+        let mut store = GothamStore::default();
+        assert!(matches!(
+            store.borrow_either::<MyStruct, AnotherStruct>(),
+            OneOf::Neither
+        ));
+        store.put(AnotherStruct { value: "a string" });
+        assert!(matches!(
+            store.borrow_either::<MyStruct, AnotherStruct>(),
+            OneOf::B(b) if b.value == "a string"
+        ));
+        store.put(MyStruct { value: 100 });
+        assert!(matches!(
+            store.borrow_either::<MyStruct, AnotherStruct>(),
+            OneOf::A(a) if a.value == 100
+        ));
+    }
+
+    #[test]
+    fn borrow_take_ctx() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        assert_eq!(store.borrow_ctx::<MyStruct>("while testing").value, 100);
+        assert_eq!(store.take_ctx::<MyStruct>("while testing").value, 100);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "while rendering template: required type gotham_store::tests::MyStruct is not present in GothamStore container"
+    )]
+    fn borrow_ctx_missing() {
+        let store = GothamStore::default();
+        let _ = store.borrow_ctx::<MyStruct>("while rendering template");
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "while shutting down: required type gotham_store::tests::MyStruct is not present in GothamStore container"
+    )]
+    fn take_ctx_missing() {
+        let mut store = GothamStore::default();
+        let _ = store.take_ctx::<MyStruct>("while shutting down");
+    }
+
+    #[test]
+    fn into_iter() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store.put(AnotherStruct { value: "a string" });
+        let entries: Vec<_> = store.into_iter().collect();
+        assert_eq!(entries.len(), 2);
+        for (id, boxed) in entries {
+            if id == TypeId::of::<MyStruct>() {
+                assert_eq!(boxed.downcast::<MyStruct>().unwrap().value, 100);
+            } else {
+                assert_eq!(id, TypeId::of::<AnotherStruct>());
+                assert_eq!(boxed.downcast::<AnotherStruct>().unwrap().value, "a string");
+            }
+        }
+    }
+
+    #[test]
+    fn replace_with() {
+        let mut store = GothamStore::default();
+        store.replace_with(|old: Option<MyStruct>| {
+            assert!(old.is_none());
+            MyStruct { value: 1 }
+        });
+        assert_eq!(store.borrow::<MyStruct>().value, 1);
+        store.replace_with(|old: Option<MyStruct>| MyStruct {
+            value: old.unwrap().value + 10,
+        });
+        assert_eq!(store.borrow::<MyStruct>().value, 11);
+    }
+
+    #[test]
+    fn borrow_or() {
+        static DEFAULT: MyStruct = MyStruct { value: 0 };
+        let mut store = GothamStore::default();
+        assert_eq!(store.borrow_or(&DEFAULT).value, 0);
+        store.put(MyStruct { value: 100 });
+        assert_eq!(store.borrow_or(&DEFAULT).value, 100);
+        let fallback = AnotherStruct { value: "fallback" };
+        assert_eq!(store.borrow_or(&fallback).value, "fallback");
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing() {
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+        #[derive(Clone, Default)]
+        struct Capture(Arc<Mutex<Vec<(String, String)>>>);
+
+        struct Fields<'a>(&'a mut Vec<(String, String)>);
+
+        impl Visit for Fields<'_> {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                self.0.push((field.name().to_string(), value.to_string()));
+            }
+
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                self.0
+                    .push((field.name().to_string(), format!("{:?}", value)));
+            }
+        }
+
+        impl<S: tracing::Subscriber> Layer<S> for Capture {
+            fn on_event(&self, event: &tracing::Event<'_>, _: Context<'_, S>) {
+                event.record(&mut Fields(&mut self.0.lock().unwrap()));
+            }
+        }
+
+        let capture = Capture::default();
+        let subscriber = tracing_subscriber::registry().with(capture.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let mut store = GothamStore::default();
+            store.put(MyStruct { value: 100 });
+        });
+
+        let fields = capture.0.lock().unwrap();
+        assert!(fields.contains(&("message".to_string(), "put".to_string())));
+        assert!(fields.contains(&(
+            "type".to_string(),
+            "gotham_store::tests::MyStruct".to_string()
+        )));
+    }
+
+    mod auth {
+        pub struct Session;
+        pub struct User;
+    }
+
+    #[test]
+    fn split_by_name_prefix() {
+        let mut store = GothamStore::default();
+        store.put(auth::Session);
+        store.put(auth::User);
+        store.put(MyStruct { value: 100 });
+        let auth = store.split_by_name_prefix("gotham_store::tests::auth::");
+        assert_eq!(auth.len(), 2);
+        assert!(auth.has::<auth::Session>());
+        assert!(auth.has::<auth::User>());
+        assert_eq!(store.len(), 1);
+        assert!(store.has::<MyStruct>());
+        assert!(store.split_by_name_prefix("other::").is_empty());
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn contains_all_types_of() {
+        let mut schema = GothamStore::default();
+        let mut store = GothamStore::default();
+        assert!(store.contains_all_types_of(&schema));
+        assert!(store.missing_types_of(&schema).is_empty());
+
+        schema.put(MyStruct { value: 0 });
+        schema.put(AnotherStruct { value: "" });
+        store.put(MyStruct { value: 100 });
+        assert!(!store.contains_all_types_of(&schema));
+        assert_eq!(
+            store.missing_types_of(&schema),
+            vec![TypeId::of::<AnotherStruct>()]
+        );
+
+        store.put(AnotherStruct { value: "a string" });
+        store.put(42u32);
+        assert!(store.contains_all_types_of(&schema));
+        assert!(store.missing_types_of(&schema).is_empty());
+    }
+
+    #[test]
+    fn modify_then_read() {
+        let mut store = GothamStore::default();
+        assert!(store
+            .modify_then_read(|s: &mut MyStruct| s.value += 1, |s| s.value)
+            .is_none());
+        store.put(MyStruct { value: 100 });
+        assert_eq!(
+            store.modify_then_read(|s: &mut MyStruct| s.value += 10, |s| s.value),
+            Some(110)
+        );
+        assert_eq!(store.borrow::<MyStruct>().value, 110);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn put_json_get_as() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Config {
+            name: String,
+            port: u16,
+        }
+
+        let mut store = GothamStore::default();
+        assert!(store.get_as::<Config>("config").unwrap().is_none());
+        store.put_json(
+            "config",
+            serde_json::json!({ "name": "server", "port": 8080 }),
+        );
+        assert_eq!(
+            store.get_as::<Config>("config").unwrap(),
+            Some(Config {
+                name: "server".to_string(),
+                port: 8080,
+            })
+        );
+        assert!(matches!(
+            store.get_as::<u16>("config"),
+            Err(StoreError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn freeze_type() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store.put(AnotherStruct { value: "a string" });
+        store.freeze_type::<MyStruct>();
+        assert!(store.is_frozen::<MyStruct>());
+        assert!(!store.is_frozen::<AnotherStruct>());
+        assert_eq!(store.borrow::<MyStruct>().value, 100);
+        assert!(store.try_take::<MyStruct>().is_none());
+        assert!(!store.move_type_into::<MyStruct>(&mut GothamStore::default()));
+        assert_eq!(store.borrow::<MyStruct>().value, 100);
+        store.put(AnotherStruct {
+            value: "another string",
+        });
+        assert_eq!(store.take::<AnotherStruct>().value, "another string");
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "type gotham_store::tests::MyStruct is frozen in GothamStore container"
+    )]
+    fn freeze_type_put() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store.freeze_type::<MyStruct>();
+        store.put(MyStruct { value: 110 });
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "type gotham_store::tests::MyStruct is frozen in GothamStore container"
+    )]
+    fn freeze_type_take() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store.freeze_type::<MyStruct>();
+        store.take::<MyStruct>();
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "type gotham_store::tests::MyStruct is frozen in GothamStore container"
+    )]
+    fn freeze_type_absent() {
+        let mut store = GothamStore::default();
+        store.freeze_type::<MyStruct>();
+        store.put(MyStruct { value: 100 });
+    }
+
+    #[test]
+    fn type_names() {
+        let mut store = GothamStore::default();
+        assert!(store.type_names().is_empty());
+        store.put(MyStruct { value: 100 });
+        store.put(AnotherStruct { value: "a string" });
+        store.insert_any(TypeId::of::<u32>(), Box::new(1u32));
+        let mut names = store.type_names();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "gotham_store::tests::AnotherStruct",
+                "gotham_store::tests::MyStruct"
+            ]
+        );
+    }
+
+    #[test]
+    fn readonly() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        let readonly = store.readonly();
+        assert!(readonly.has::<MyStruct>());
+        assert!(!readonly.has::<AnotherStruct>());
+        assert_eq!(readonly.try_borrow::<MyStruct>().unwrap().value, 100);
+        assert!(readonly.try_borrow::<AnotherStruct>().is_none());
+        assert_eq!(readonly.type_names(), ["gotham_store::tests::MyStruct"]);
+    }
+
+    #[test]
+    fn clone_out() {
+        let mut store = GothamStore::default();
+        store.put(1u32);
+        store.put("a string".to_string());
+        assert_eq!(
+            store.clone_out::<u32, String>(),
+            Some((1, "a string".to_string()))
+        );
+        assert!(store.clone_out3::<u32, String, bool>().is_none());
+        store.put(true);
+        assert_eq!(
+            store.clone_out3::<u32, String, bool>(),
+            Some((1, "a string".to_string(), true))
+        );
+        assert!(store.clone_out::<u32, u64>().is_none());
+    }
+
+    #[test]
+    fn replace_with_panic() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            store.replace_with(|_: Option<MyStruct>| panic!("transform failed"));
+        }));
+        assert!(result.is_err());
+        assert!(!store.has::<MyStruct>());
+        store.put(MyStruct { value: 110 });
+        assert_eq!(store.borrow::<MyStruct>().value, 110);
+    }
+
+    #[test]
+    fn entry_sizes() {
+        let mut store = GothamStore::default();
+        store.put(1u8);
+        store.put([0u64; 128]);
+        let sizes = store.entry_sizes();
+        assert_eq!(sizes.len(), 2);
+        assert!(sizes.contains(&(TypeId::of::<u8>(), 1)));
+        assert!(sizes.contains(&(TypeId::of::<[u64; 128]>(), 1024)));
+    }
+
+    #[test]
+    fn put_ref() {
+        let mut store = GothamStore::default();
+        store.put_ref(MyStruct { value: 100 }).value += 10;
+        assert_eq!(store.borrow::<MyStruct>().value, 110);
+        store.put_ref(MyStruct { value: 1 }).value += 1;
+        assert_eq!(store.borrow::<MyStruct>().value, 2);
+    }
+
+    #[test]
+    fn tagged_store() {
+        let mut store = TaggedStore::default();
+        store.put_tagged("my_struct", MyStruct { value: 100 });
+        store.put_tagged("count", 1u32);
+        assert_eq!(
+            store.get_tagged::<MyStruct>("my_struct").unwrap().value,
+            100
+        );
+        assert_eq!(store.get_tagged::<u32>("count"), Some(&1));
+        assert!(store.get_tagged::<u64>("count").is_none());
+        assert!(store.get_tagged::<u32>("missing").is_none());
+        store.put_tagged("count", 2u64);
+        assert!(store.get_tagged::<u32>("count").is_none());
+        assert_eq!(store.get_tagged::<u64>("count"), Some(&2));
+    }
+
+    #[test]
+    fn visit_mut() {
+        struct Requests(u32);
+        struct Errors(u32);
+
+        let mut store = GothamStore::default();
+        store.put(Requests(10));
+        store.put(Errors(2));
+        store.put(MyStruct { value: 100 });
+        store.visit_mut(|_, value| {
+            if let Some(requests) = value.downcast_mut::<Requests>() {
+                requests.0 = 0;
+            } else if let Some(errors) = value.downcast_mut::<Errors>() {
+                errors.0 = 0;
+            }
+        });
+        assert_eq!(store.borrow::<Requests>().0, 0);
+        assert_eq!(store.borrow::<Errors>().0, 0);
+        assert_eq!(store.borrow::<MyStruct>().value, 100);
+    }
+
+    #[test]
+    fn get2_get3() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store.put(1u32);
+        let (a, b) = store.get2::<MyStruct, AnotherStruct>();
+        assert_eq!(a.unwrap().value, 100);
+        assert!(b.is_none());
+        let (a, b, c) = store.get3::<AnotherStruct, MyStruct, u32>();
+        assert!(a.is_none());
+        assert_eq!(b.unwrap().value, 100);
+        assert_eq!(c, Some(&1));
+    }
+
+    #[test]
+    fn borrow_or_store_default() {
+        use std::cell::Cell;
+
+        thread_local! {
+            static CONSTRUCTED: Cell<u32> = const { Cell::new(0) };
+        }
+
+        struct Settings {
+            retries: u32,
+        }
+
+        impl StoreDefault for Settings {
+            fn store_default() -> Self {
+                CONSTRUCTED.with(|c| c.set(c.get() + 1));
+                Settings { retries: 3 }
+            }
+        }
+
+        let mut store = GothamStore::default();
+        assert!(!store.has::<Settings>());
+        assert_eq!(store.borrow_or_store_default::<Settings>().retries, 3);
+        store.borrow_or_store_default::<Settings>().retries += 1;
+        assert_eq!(store.borrow::<Settings>().retries, 4);
+        assert_eq!(CONSTRUCTED.with(Cell::get), 1);
+    }
+
+    #[test]
+    fn rewrap() {
+        struct Wrapper(MyStruct);
+
+        let mut store = GothamStore::default();
+        assert!(!store.rewrap(Wrapper));
+        store.put(MyStruct { value: 100 });
+        assert!(store.rewrap(Wrapper));
+        assert!(!store.has::<MyStruct>());
+        assert_eq!(store.borrow::<Wrapper>().0.value, 100);
+
+        store.put(MyStruct { value: 1 });
+        store.freeze_type::<Wrapper>();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            store.rewrap(Wrapper);
+        }));
+        assert!(result.is_err());
+        assert_eq!(store.borrow::<MyStruct>().value, 1);
+        assert_eq!(store.borrow::<Wrapper>().0.value, 100);
+
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 2 });
+        store.freeze_type::<MyStruct>();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            store.rewrap(Wrapper);
+        }));
+        assert!(result.is_err());
+        assert_eq!(store.borrow::<MyStruct>().value, 2);
+        assert!(!store.has::<Wrapper>());
+    }
+
+    #[test]
+    fn count_by_name_prefix() {
+        let mut store = GothamStore::default();
+        store.put(auth::Session);
+        store.put(auth::User);
+        store.put(MyStruct { value: 100 });
+        store.put(1u32);
+        assert_eq!(store.count_by_name_prefix("gotham_store::tests::auth::"), 2);
+        assert_eq!(store.count_by_name_prefix("gotham_store::tests::"), 3);
+        assert_eq!(store.count_by_name_prefix("other::"), 0);
+    }
+
+    #[test]
+    fn into_sync() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let mut store = GothamStore::default();
+        store.put_sync(1u32);
+        store.put_sync("a string".to_string());
+        let mut sync = store.into_sync().unwrap();
+        assert_send_sync(&sync);
+        assert_eq!(sync.len(), 2);
+        assert_eq!(*sync.borrow::<u32>(), 1);
+        assert_eq!(sync.take::<String>(), "a string");
+        assert!(!sync.has::<String>());
+    }
+
+    #[test]
+    fn into_sync_mixed() {
+        let mut store = GothamStore::default();
+        store.put_sync(1u32);
+        store.put(Rc::new(MyStruct { value: 100 }));
+        let store = store.into_sync().unwrap_err();
+        assert_eq!(store.len(), 2);
+        assert_eq!(*store.borrow::<u32>(), 1);
+
+        let mut store = GothamStore::default();
+        store.put_sync(1u32);
+        store.put(2u32);
+        assert!(store.into_sync().is_err());
+    }
+
+    #[test]
+    fn as_ptr() {
+        let mut store = GothamStore::default();
+        assert!(store.as_ptr::<MyStruct>().is_none());
+        assert!(store.as_mut_ptr::<MyStruct>().is_none());
+        store.put(MyStruct { value: 100 });
+        let ptr = store.as_ptr::<MyStruct>().unwrap();
+        assert_eq!(unsafe { (*ptr).value }, 100);
+        assert!(std::ptr::eq(ptr, store.borrow::<MyStruct>()));
+        let ptr = store.as_mut_ptr::<MyStruct>().unwrap();
+        unsafe { (*ptr).value += 10 };
+        assert_eq!(store.borrow::<MyStruct>().value, 110);
+    }
+
+    #[test]
+    fn remove_many() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store.put(AnotherStruct { value: "a string" });
+        store.put(1u32);
+        let removed = store.remove_many(&[
+            TypeId::of::<MyStruct>(),
+            TypeId::of::<u32>(),
+            TypeId::of::<u64>(),
+        ]);
+        assert_eq!(removed, 2);
+        assert_eq!(store.len(), 1);
+        assert!(store.has::<AnotherStruct>());
+    }
+
+    #[test]
+    fn write_type_names() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store.put(1u32);
+        let mut out = String::new();
+        store.write_type_names(&mut out).unwrap();
+        let mut lines: Vec<_> = out.lines().collect();
+        lines.sort();
+        assert_eq!(lines, ["gotham_store::tests::MyStruct", "u32"]);
+        assert!(out.ends_with('\n'));
+    }
+
+    #[test]
+    fn take_guard() {
+        let mut store = GothamStore::default();
+        assert!(store.take_guard::<MyStruct>().is_none());
+        store.put(MyStruct { value: 100 });
+        {
+            let mut guard = store.take_guard::<MyStruct>().unwrap();
+            guard.value += 10;
+        }
+        assert_eq!(store.borrow::<MyStruct>().value, 110);
+        let guard = store.take_guard::<MyStruct>().unwrap();
+        assert_eq!(guard.commit().value, 110);
+        assert!(!store.has::<MyStruct>());
+    }
+
+    #[test]
+    fn len_many() {
+        macro_rules! put_arrays {
+            ($store:ident, $($n:literal)*) => {
+                $( $store.put([0u8; $n]); )*
+            };
+        }
+
+        let mut store = GothamStore::default();
+        put_arrays!(store, 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19
+                           20 21 22 23 24 25 26 27 28 29 30 31 32 33 34 35 36 37 38 39
+                           40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59);
+        assert_eq!(store.len(), 60);
+        put_arrays!(store, 0 1 2 3 4 5 6 7 8 9);
+        assert_eq!(store.len(), 60);
+        store.take::<[u8; 0]>();
+        store.take::<[u8; 30]>();
+        store.take::<[u8; 59]>();
+        assert_eq!(store.len(), 57);
+        assert!(!store.is_empty());
+    }
+
+    #[test]
+    fn replace_any() {
+        let mut src = GothamStore::default();
+        src.put(MyStruct { value: 100 });
+        let (_, boxed) = src.into_iter().next().unwrap();
+
+        let mut dest = GothamStore::default();
+        dest.put(MyStruct { value: 1 });
+        let old = dest.replace_any(boxed).unwrap();
+        assert_eq!(old.downcast_ref::<MyStruct>().unwrap().value, 1);
+        assert_eq!(dest.borrow::<MyStruct>().value, 100);
+        assert!(dest.replace_any(Box::new(1u32)).is_none());
+        assert_eq!(*dest.borrow::<u32>(), 1);
+    }
+
+    #[test]
+    fn require() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store.put(AnotherStruct { value: "a string" });
+        crate::require!(store => my: MyStruct, another: AnotherStruct);
+        assert_eq!(my.value, 100);
+        assert_eq!(another.value, "a string");
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "required binding `another` of type gotham_store::tests::AnotherStruct is not present in GothamStore container"
+    )]
+    fn require_missing() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        crate::require!(store => _my: MyStruct, another: AnotherStruct);
+        let _ = another;
+    }
+
+    #[test]
+    fn collect_map() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store.put(AnotherStruct { value: "a string" });
+        store.put(1i32);
+        let values = store.collect_map(|_, value| {
+            value
+                .downcast_ref::<MyStruct>()
+                .map(|s| s.value)
+                .or_else(|| value.downcast_ref::<i32>().copied())
+        });
+        assert_eq!(values.len(), 2);
+        assert!(values.contains(&100));
+        assert!(values.contains(&1));
+    }
+
+    #[test]
+    fn observers() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut store = GothamStore::default();
+        let (my, another) = (seen.clone(), seen.clone());
+        store
+            .observers()
+            .on::<MyStruct>(move |s| my.borrow_mut().push(format!("my {}", s.value)))
+            .on::<AnotherStruct>(move |s| {
+                another.borrow_mut().push(format!("another {}", s.value))
+            });
+
+        store.put(MyStruct { value: 100 });
+        store.put(1u32);
+        store.put(AnotherStruct { value: "a string" });
+        store.put_ref(MyStruct { value: 110 });
+        assert_eq!(*seen.borrow(), ["my 100", "another a string", "my 110"]);
+    }
+
+    #[test]
+    fn into_shared() {
+        let mut store = GothamStore::default();
+        assert!(store.into_shared::<MyStruct>().is_none());
+        store.put(MyStruct { value: 100 });
+        let shared = store.into_shared::<MyStruct>().unwrap();
+        assert_eq!(shared.value, 100);
+        assert!(!store.has::<MyStruct>());
+        let another = store.get_shared::<MyStruct>().unwrap();
+        assert!(Rc::ptr_eq(&shared, &another));
+        assert_eq!(Rc::strong_count(&shared), 3);
+    }
+
+    #[test]
+    fn remove_by_name_prefix() {
+        let mut store = GothamStore::default();
+        store.put(auth::Session);
+        store.put(auth::User);
+        store.put(MyStruct { value: 100 });
+        assert_eq!(
+            store.remove_by_name_prefix("gotham_store::tests::auth::"),
+            2
+        );
+        assert!(!store.has::<auth::Session>());
+        assert!(!store.has::<auth::User>());
+        assert!(store.has::<MyStruct>());
+        assert_eq!(
+            store.remove_by_name_prefix("gotham_store::tests::auth::"),
+            0
+        );
+    }
+
+    #[test]
+    fn counts_by_crate() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store.put(AnotherStruct { value: "a string" });
+        store.put("a string".to_string());
+        store.put(1u32);
+        store.put((1u8, "a string".to_string()));
+        store.put(vec![1u8]);
+        store.put((|x| x) as fn(u8) -> u8);
+        let counts = store.counts_by_crate();
+        assert_eq!(counts.len(), 5);
+        assert_eq!(counts["gotham_store"], 2);
+        assert_eq!(counts["alloc"], 2);
+        assert_eq!(counts["u32"], 1);
+        assert_eq!(counts["(u8, alloc::string::String)"], 1);
+        assert_eq!(counts["fn(u8) -> u8"], 1);
+    }
+
+    #[test]
+    fn replace_if() {
+        let mut store = GothamStore::default();
+        assert!(matches!(
+            store.replace_if(MyStruct { value: 1 }, |_| false),
+            Ok(None)
+        ));
+        assert_eq!(store.borrow::<MyStruct>().value, 1);
+        assert!(matches!(
+            store.replace_if(MyStruct { value: 2 }, |old| old.value == 1),
+            Ok(Some(MyStruct { value: 1 }))
+        ));
+        assert_eq!(store.borrow::<MyStruct>().value, 2);
+        assert!(matches!(
+            store.replace_if(MyStruct { value: 3 }, |old| old.value == 1),
+            Err(MyStruct { value: 3 })
+        ));
+        assert_eq!(store.borrow::<MyStruct>().value, 2);
+    }
+
+    #[test]
+    fn type_id_snapshot() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        let before = store.type_id_snapshot();
+        store.put(AnotherStruct { value: "a string" });
+        store.put(MyStruct { value: 110 });
+        store.take::<MyStruct>();
+        let after = store.type_id_snapshot();
+        assert_eq!(
+            added_between(&before, &after),
+            [TypeId::of::<AnotherStruct>()]
+        );
+        assert!(added_between(&after, &after).is_empty());
+    }
+
+    #[test]
+    fn load_env() {
+        const VAR: &str = "GOTHAM_STORE_TEST_LOAD_ENV_PORT";
+
+        struct Port(u16);
+
+        impl FromEnv for Port {
+            fn from_env() -> Option<Self> {
+                std::env::var(VAR).ok()?.parse().ok().map(Port)
+            }
+        }
+
+        let mut store = GothamStore::default();
+        std::env::remove_var(VAR);
+        assert!(!store.load_env::<Port>());
+        assert!(!store.has::<Port>());
+        std::env::set_var(VAR, "8080");
+        assert!(store.load_env::<Port>());
+        assert_eq!(store.borrow::<Port>().0, 8080);
+        std::env::remove_var(VAR);
+    }
+
+    #[test]
+    fn drain_filter() {
+        let mut store = GothamStore::default();
+        store.put(1u32);
+        store.put(2u64);
+        store.put(MyStruct { value: 100 });
+        let drained = store.drain_filter(|_, value| value.is::<u32>() || value.is::<u64>());
+        assert_eq!(drained.len(), 2);
+        let sum: u64 = drained
+            .iter()
+            .map(|(_, b)| {
+                b.downcast_ref::<u32>()
+                    .map(|v| u64::from(*v))
+                    .or_else(|| b.downcast_ref::<u64>().copied())
+                    .unwrap()
+            })
+            .sum();
+        assert_eq!(sum, 3);
+        assert_eq!(store.len(), 1);
+        assert!(store.has::<MyStruct>());
+    }
+
+    #[test]
+    fn content_hash() {
+        let mut store = GothamStore::default();
+        let empty = store.content_hash();
+        store.put_hashable(1u32);
+        store.put_hashable("a string".to_string());
+        let hash = store.content_hash();
+        assert_ne!(hash, empty);
+        assert_eq!(*store.borrow::<u32>(), 1);
+        store.put(MyStruct { value: 100 });
+        assert_eq!(store.content_hash(), hash);
+        store.put_hashable(2u32);
+        assert_ne!(store.content_hash(), hash);
+        store.put_hashable(1u32);
+        assert_eq!(store.content_hash(), hash);
+    }
+
+    #[test]
+    fn borrow_as_slice() {
+        let mut store = GothamStore::default();
+        assert!(store.borrow_as_slice::<i32>().is_none());
+        store.put(vec![1, 2, 3]);
+        let slice = store.borrow_as_slice::<i32>().unwrap();
+        assert_eq!(slice.len(), 3);
+        assert_eq!(slice, [1, 2, 3]);
+        assert!(store.borrow_as_slice::<u32>().is_none());
+    }
+
+    #[test]
+    fn release_shared() {
+        let mut store = GothamStore::default();
+        assert!(store.release_shared::<MyStruct>().is_none());
+        store.put(MyStruct { value: 100 });
+        let shared = store.into_shared::<MyStruct>().unwrap();
+        assert_eq!(Rc::strong_count(&shared), 2);
+        let released = store.release_shared::<MyStruct>().unwrap();
+        assert!(store.get_shared::<MyStruct>().is_none());
+        assert!(store.is_empty());
+        drop(released);
+        assert_eq!(Rc::strong_count(&shared), 1);
+    }
+
+    #[test]
+    fn eq_on() {
+        let mut a = GothamStore::default();
+        let mut b = GothamStore::default();
+        assert!(a.eq_on::<u32>(&b));
+        a.put(1u32);
+        assert!(!a.eq_on::<u32>(&b));
+        assert!(!b.eq_on::<u32>(&a));
+        b.put(2u32);
+        assert!(!a.eq_on::<u32>(&b));
+        b.put(1u32);
+        a.put(MyStruct { value: 100 });
+        assert!(a.eq_on::<u32>(&b) && a.eq_on::<String>(&b));
+    }
+
+    #[test]
+    fn insert() {
+        let mut store = GothamStore::default();
+        store
+            .insert(MyStruct { value: 100 })
+            .insert(AnotherStruct { value: "a string" })
+            .insert(1u32);
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.borrow::<MyStruct>().value, 100);
+        assert_eq!(store.borrow::<AnotherStruct>().value, "a string");
+        assert_eq!(*store.borrow::<u32>(), 1);
+    }
+
+    #[test]
+    fn compute_cached() {
+        use std::cell::Cell;
+
+        let runs = Cell::new(0);
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        let compute = || {
+            runs.set(runs.get() + 1);
+            format!("value is {}", store.borrow::<MyStruct>().value)
+        };
+        let first = store.compute_cached(compute);
+        let second = store.compute_cached(compute);
+        store.compute_cached(|| 1u32);
+        assert_eq!(first, "value is 100");
+        assert!(std::ptr::eq(first, second));
+        assert_eq!(runs.get(), 1);
+        assert!(!store.has::<String>());
+
+        store.clear_cached();
+        store.compute_cached(|| {
+            runs.set(runs.get() + 1);
+            String::new()
+        });
+        assert_eq!(runs.get(), 2);
+    }
+
+    #[test]
+    fn compute_cached_reentrant() {
+        let store = GothamStore::default();
+        let mut inner = None;
+        let outer = store.compute_cached(|| {
+            inner = Some(store.compute_cached(|| 1u32));
+            2u32
+        });
+        assert_eq!(*outer, 1);
+        assert!(std::ptr::eq(outer, inner.unwrap()));
+    }
+
+    #[test]
+    fn for_each_type_name() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store.put(1u32);
+        let mut names = Vec::new();
+        store.for_each_type_name(|name| names.push(name));
+        names.sort();
+        assert_eq!(names, ["gotham_store::tests::MyStruct", "u32"]);
+    }
+
+    #[test]
+    fn borrow_or_drop() {
+        let mut store = GothamStore::default();
+        assert!(store.borrow_or_drop::<MyStruct>().is_none());
+        store.put(MyStruct { value: 100 });
+        assert_eq!(store.borrow_or_drop::<MyStruct>().unwrap().value, 100);
+        store.data.insert(
+            TypeId::of::<MyStruct>(),
+            Box::new(AnotherStruct { value: "a" }),
+        );
+        assert!(store.borrow_or_drop::<MyStruct>().is_none());
+        assert!(!store.has::<MyStruct>());
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn stats() {
+        let mut store = GothamStore::default();
+        assert_eq!(store.stats(), Default::default());
+        store.put(MyStruct { value: 100 });
+        store.put([0u64; 16]);
+        store.insert_any(TypeId::of::<u8>(), Box::new(1u8));
+        let stats = store.stats();
+        assert_eq!(stats.entry_count, store.len());
+        assert_eq!(
+            stats.total_value_bytes,
+            store
+                .entry_sizes()
+                .iter()
+                .map(|(_, size)| size)
+                .sum::<usize>()
+        );
+        assert_eq!(stats.type_names, store.type_names());
+    }
+
+    #[test]
+    fn move_all_into() {
+        let mut src = GothamStore::default();
+        let mut dest = GothamStore::default();
+        src.put(MyStruct { value: 100 });
+        src.put(AnotherStruct { value: "a string" });
+        dest.put(MyStruct { value: 1 });
+        dest.put(1u32);
+        src.move_all_into(&mut dest);
+        assert!(src.is_empty());
+        assert_eq!(dest.len(), 3);
+        assert_eq!(dest.borrow::<MyStruct>().value, 100);
+        assert_eq!(dest.borrow::<AnotherStruct>().value, "a string");
+        assert_eq!(
+            dest.type_names().len(),
+            3,
+            "captured type names move along with the values"
+        );
+
+        src.put(MyStruct { value: 2 });
+        src.put(AnotherStruct { value: "another" });
+        dest.freeze_type::<MyStruct>();
+        src.move_all_into(&mut dest);
+        assert_eq!(src.borrow::<MyStruct>().value, 2);
+        assert_eq!(dest.borrow::<MyStruct>().value, 100);
+        assert_eq!(dest.borrow::<AnotherStruct>().value, "another");
+        assert_eq!(src.len(), 1);
+    }
+
+    #[test]
+    fn try_insert_any() {
+        let mut store = GothamStore::default();
+        let id = TypeId::of::<MyStruct>();
+        assert!(store
+            .try_insert_any(id, Box::new(MyStruct { value: 1 }))
+            .unwrap()
+            .is_none());
+        let old = store
+            .try_insert_any(id, Box::new(MyStruct { value: 2 }))
+            .unwrap()
+            .unwrap();
+        assert_eq!(old.downcast_ref::<MyStruct>().unwrap().value, 1);
+        assert!(matches!(
+            store.try_insert_any(id, Box::new(AnotherStruct { value: "a" })),
+            Err(StoreError::TypeMismatch)
+        ));
+        assert_eq!(store.borrow::<MyStruct>().value, 2);
+        assert_eq!(store.len(), 1);
+
+        store.freeze_type::<MyStruct>();
+        assert!(matches!(
+            store.try_insert_any(id, Box::new(MyStruct { value: 3 })),
+            Err(StoreError::Frozen("gotham_store::tests::MyStruct"))
+        ));
+        store.seal();
+        assert!(matches!(
+            store.try_insert_any(TypeId::of::<u8>(), Box::new(1u8)),
+            Err(StoreError::Sealed)
+        ));
+        assert_eq!(store.borrow::<MyStruct>().value, 2);
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn type_store_traits() {
+        fn read(store: &impl TypeStore) -> Option<i32> {
+            store.try_borrow::<MyStruct>().map(|s| s.value)
+        }
+
+        fn bump(store: &mut impl TypeStoreMut) {
+            match store.try_borrow_mut::<MyStruct>() {
+                Some(s) => s.value += 1,
+                None => store.put(MyStruct { value: 0 }),
+            }
+        }
+
+        let mut store = GothamStore::default();
+        assert_eq!(read(&store), None);
+        bump(&mut store);
+        bump(&mut store);
+        assert_eq!(read(&store), Some(1));
+        assert_eq!(read(&store.readonly()), Some(1));
+        assert!(TypeStore::has::<MyStruct>(&store));
+
+        let mut sync = GothamStore::default();
+        sync.put_sync(MyStruct { value: 5 });
+        let sync = sync.into_sync().unwrap();
+        assert_eq!(read(&sync), Some(5));
+    }
+
+    #[test]
+    fn put_overwrites_in_place() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 1 });
+        let before = store.borrow::<MyStruct>() as *const MyStruct;
+        store.put(MyStruct { value: 2 });
+        let after = store.borrow::<MyStruct>() as *const MyStruct;
+        assert_eq!(before, after);
+        assert_eq!(store.borrow::<MyStruct>().value, 2);
+        assert_eq!(store.type_names(), vec![std::any::type_name::<MyStruct>()]);
+
+        let seen = Rc::new(std::cell::Cell::new(0));
+        let seen2 = Rc::clone(&seen);
+        store
+            .observers()
+            .on::<MyStruct>(move |s| seen2.set(s.value));
+        store.put(MyStruct { value: 3 });
+        assert_eq!(seen.get(), 3);
+    }
+
+    #[test]
+    fn sorted_type_ids() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 1 });
+        store.put(AnotherStruct { value: "a" });
+        store.put(1u8);
+        let ids = store.sorted_type_ids();
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(ids, store.keys().copied().collect::<Vec<_>>());
+        assert!(ids.binary_search(&TypeId::of::<u8>()).is_ok());
+        assert!(ids.binary_search(&TypeId::of::<u16>()).is_err());
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn log() {
+        use std::sync::Mutex;
+
+        static RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        struct Capture;
+
+        impl log::Log for Capture {
+            fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record<'_>) {
+                RECORDS.lock().unwrap().push(record.args().to_string());
+            }
+
+            fn flush(&self) {}
+        }
+
+        log::set_logger(&Capture).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+
+        let records = RECORDS.lock().unwrap();
+        assert!(records.contains(&"put gotham_store::tests::MyStruct".to_string()));
+    }
+
+    #[test]
+    fn put_ordered() {
+        use std::cell::RefCell;
+
+        struct Noisy<const N: usize>(Rc<RefCell<Vec<usize>>>);
+
+        impl<const N: usize> Drop for Noisy<N> {
+            fn drop(&mut self) {
+                self.0.borrow_mut().push(N);
+            }
+        }
+
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut store = GothamStore::default();
+        store.put_ordered(10, Noisy::<1>(Rc::clone(&log)));
+        store.put(Noisy::<2>(Rc::clone(&log)));
+        store.put_ordered(-1, Noisy::<3>(Rc::clone(&log)));
+        drop(store);
+        assert_eq!(*log.borrow(), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn set_missing_formatter() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let mut store = GothamStore::default();
+        store.set_missing_formatter(|name| format!("no {} configured", name));
+        let err = catch_unwind(AssertUnwindSafe(|| {
+            store.borrow::<MyStruct>();
+        }))
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<String>().unwrap(),
+            "no gotham_store::tests::MyStruct configured"
+        );
+        let err = catch_unwind(AssertUnwindSafe(|| store.take::<u8>())).unwrap_err();
+        assert_eq!(err.downcast_ref::<String>().unwrap(), "no u8 configured");
+    }
+
+    #[test]
+    fn iter_type() {
+        let mut store = GothamStore::default();
+        assert_eq!(store.iter_type::<MyStruct>().count(), 0);
+        store.put(MyStruct { value: 100 });
+        let values: Vec<_> = store.iter_type::<MyStruct>().map(|s| s.value).collect();
+        assert_eq!(values, vec![100]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn to_sorted_entries() {
+        let mut store = GothamStore::default();
+        store.put_json("port", serde_json::json!(8080));
+        store.put_json("name", serde_json::json!("server"));
+        let entries = store.to_sorted_entries();
+        assert_eq!(
+            entries,
+            vec![
+                ("name".to_string(), serde_json::json!("server")),
+                ("port".to_string(), serde_json::json!(8080)),
+            ]
+        );
+
+        let mut copy = GothamStore::default();
+        for (key, value) in entries {
+            copy.put_json(&key, value);
+        }
+        assert_eq!(copy.get_as::<u16>("port").unwrap(), Some(8080));
+        assert_eq!(copy.to_sorted_entries(), store.to_sorted_entries());
+    }
+
+    #[test]
+    fn watch() {
+        let mut store = GothamStore::default();
+        let first = store.watch();
+        let second = store.watch();
+        store.put(MyStruct { value: 1 });
+        store.put(MyStruct { value: 2 });
+        assert_eq!(first.try_recv(), Ok(TypeId::of::<MyStruct>()));
+        assert_eq!(first.try_recv(), Ok(TypeId::of::<MyStruct>()));
+        assert!(first.try_recv().is_err());
+        assert_eq!(second.try_iter().count(), 2);
+
+        drop(second);
+        store.take::<MyStruct>();
+        store.put(AnotherStruct { value: "a" });
+        assert_eq!(
+            first.try_iter().collect::<Vec<_>>(),
+            vec![TypeId::of::<MyStruct>(), TypeId::of::<AnotherStruct>()]
+        );
+        assert_eq!(store.watchers.len(), 1);
+    }
+
+    #[test]
+    fn borrow_pair_mut_checked() {
+        let mut store = GothamStore::default();
+        assert!(matches!(
+            store.borrow_pair_mut_checked::<MyStruct, MyStruct>(),
+            Err(StoreError::SameType)
+        ));
+        store.put(MyStruct { value: 1 });
+        assert!(matches!(
+            store.borrow_pair_mut_checked::<MyStruct, AnotherStruct>(),
+            Err(StoreError::Missing("gotham_store::tests::AnotherStruct"))
+        ));
+        assert!(matches!(
+            store.borrow_pair_mut_checked::<AnotherStruct, MyStruct>(),
+            Err(StoreError::Missing("gotham_store::tests::AnotherStruct"))
+        ));
+        store.put(AnotherStruct { value: "a" });
+        let (a, b) = store
+            .borrow_pair_mut_checked::<MyStruct, AnotherStruct>()
+            .unwrap();
+        a.value += 1;
+        b.value = "b";
+        assert_eq!(store.borrow::<MyStruct>().value, 2);
+        assert_eq!(store.borrow::<AnotherStruct>().value, "b");
+    }
+
+    #[test]
+    fn seal() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        *store.keyed_entry::<u16>("a") = 1;
+        assert!(!store.is_sealed());
+        store.seal();
+        assert!(store.is_sealed());
+        assert_eq!(store.borrow::<MyStruct>().value, 100);
+        assert!(store.try_take::<MyStruct>().is_none());
+        assert_eq!(store.remove_by_name_prefix("gotham_store::"), 0);
+
+        let err = catch_unwind(AssertUnwindSafe(|| store.put(1u8))).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<&str>(),
+            Some(&"GothamStore container is sealed")
+        );
+        assert!(catch_unwind(AssertUnwindSafe(|| store.take::<MyStruct>())).is_err());
+        assert_eq!(store.len(), 1);
+
+        assert!(catch_unwind(AssertUnwindSafe(|| *store.keyed_entry::<u8>("a") += 1)).is_err());
+        assert!(catch_unwind(AssertUnwindSafe(|| {
+            store.keyed_get_or_insert_with("a", || 1u8);
+        }))
+        .is_err());
+        assert!(catch_unwind(AssertUnwindSafe(|| store.put_indexed(0, 1u8))).is_err());
+        assert_eq!(store.keyed().len(), 1);
+
+        *store.keyed_entry::<u16>("a") += 1;
+        *store.keyed_get_or_insert_with("a", || 0u16) += 1;
+        assert_eq!(store.keyed().get_keyed::<u16>("a"), Some(&3));
+    }
+
+    #[test]
+    fn resolve() {
+        struct Config(u16);
+        struct Pool(u16);
+        struct Service(String);
+
+        impl Provide for Config {
+            type Deps = ();
+            fn provide(_: &GothamStore) -> Self {
+                Config(5432)
+            }
+        }
+
+        impl Provide for Pool {
+            type Deps = (Config,);
+            fn provide(store: &GothamStore) -> Self {
+                Pool(store.borrow::<Config>().0)
+            }
+        }
+
+        impl Provide for Service {
+            type Deps = (Pool, Config);
+            fn provide(store: &GothamStore) -> Self {
+                Service(format!("pool on port {}", store.borrow::<Pool>().0))
+            }
+        }
+
+        let mut store = GothamStore::default();
+        let order = store.watch();
+        assert_eq!(store.resolve::<Service>().0, "pool on port 5432");
+        assert_eq!(
+            order.try_iter().collect::<Vec<_>>(),
+            vec![
+                TypeId::of::<Config>(),
+                TypeId::of::<Pool>(),
+                TypeId::of::<Service>()
+            ]
+        );
+        store.put(Config(1));
+        assert_eq!(store.resolve::<Pool>().0, 5432);
+    }
+
+    #[test]
+    #[should_panic(expected = "dependency cycle detected in GothamStore container: \
+        gotham_store::tests::resolve_cycle::A -> gotham_store::tests::resolve_cycle::B -> \
+        gotham_store::tests::resolve_cycle::A")]
+    fn resolve_cycle() {
+        struct A;
+        struct B;
+
+        impl Provide for A {
+            type Deps = (B,);
+            fn provide(_: &GothamStore) -> Self {
+                A
+            }
+        }
+
+        impl Provide for B {
+            type Deps = (A,);
+            fn provide(_: &GothamStore) -> Self {
+                B
+            }
+        }
+
+        GothamStore::default().resolve::<A>();
+    }
+
+    #[test]
+    fn keyed_entry() {
+        #[derive(Default)]
+        struct Connection {
+            requests: u32,
+        }
+
+        let mut store = GothamStore::default();
+        store.keyed_entry::<Connection>("a").requests += 1;
+        store.keyed_entry::<Connection>("b").requests += 10;
+        store.keyed_entry::<Connection>("a").requests += 1;
+        *store.keyed_get_or_insert_with("a", || 5u8) += 1;
+        assert_eq!(
+            store.keyed().get_keyed::<Connection>("a").unwrap().requests,
+            2
+        );
+        assert_eq!(
+            store.keyed().get_keyed::<Connection>("b").unwrap().requests,
+            10
+        );
+        assert_eq!(store.keyed().get_keyed::<u8>("a"), Some(&6));
+        assert!(store.keyed().get_keyed::<u8>("b").is_none());
+        assert_eq!(store.keyed().len(), 3);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn validate() {
+        let mut store = GothamStore::default();
+        assert!(store.validate().is_ok());
+        store.put(MyStruct { value: 100 });
+        store.insert_any(TypeId::of::<u8>(), Box::new(1u8));
+        assert!(store.validate().is_ok());
+        store.data.insert(
+            TypeId::of::<MyStruct>(),
+            Box::new(AnotherStruct { value: "a" }),
+        );
+        match store.validate() {
+            Err(StoreError::Inconsistent { key, actual }) => {
+                assert_eq!(key, TypeId::of::<MyStruct>());
+                assert_eq!(actual, TypeId::of::<AnotherStruct>());
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn prune_dead_weaks() {
+        let my_struct = Rc::new(MyStruct { value: 100 });
+        let another = Rc::new(AnotherStruct { value: "a" });
+        let number = Rc::new(1u8);
+        let mut store = GothamStore::default();
+        store.put_weak(Rc::downgrade(&my_struct));
+        store.put_weak(Rc::downgrade(&another));
+        store.put_weak(Rc::downgrade(&number));
+        store.put(Rc::downgrade(&Rc::new(2u16)));
+        assert_eq!(store.prune_dead_weaks(), 0);
+
+        drop(another);
+        drop(number);
+        assert_eq!(store.prune_dead_weaks(), 2);
+        assert_eq!(store.len(), 2);
+        let weak = store.borrow::<std::rc::Weak<MyStruct>>();
+        assert_eq!(weak.upgrade().unwrap().value, 100);
+        assert!(store.has::<std::rc::Weak<u16>>());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(
+        expected = "entry for type gotham_store::tests::MyStruct holds a value \
+        of a different type in GothamStore container"
+    )]
+    fn try_borrow_wrong_type() {
+        let mut store = GothamStore::default();
+        store.put::<Alias1>("alias".to_string());
+        assert_eq!(store.try_borrow::<Alias2>().unwrap(), "alias");
+        store.data.insert(
+            TypeId::of::<MyStruct>(),
+            Box::new(AnotherStruct { value: "a" }),
+        );
+        store.try_borrow::<MyStruct>();
+    }
+
+    #[test]
+    fn register_global_default() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let mut store = GothamStore::default();
+        store.register_global_default::<Alias1>("default".to_string());
+        assert_eq!(store.borrow::<String>(), "default");
+        assert_eq!(store.take::<String>(), "default");
+        assert!(store.try_borrow::<String>().is_none());
+        store.put("value".to_string());
+        assert_eq!(store.take::<String>(), "value");
+        assert_eq!(store.borrow::<String>(), "default");
+
+        assert!(catch_unwind(AssertUnwindSafe(|| store.borrow::<MyStruct>())).is_err());
+        assert!(catch_unwind(AssertUnwindSafe(|| store.take::<MyStruct>())).is_err());
+    }
+
+    #[test]
+    fn map_any() {
+        let mut store = GothamStore::default();
+        assert!(!store.map_any(TypeId::of::<u32>(), |b| b).unwrap());
+        store.put(41u32);
+        let mapped = store.map_any(TypeId::of::<u32>(), |b| {
+            Box::new(*b.downcast::<u32>().unwrap() + 1)
+        });
+        assert!(mapped.unwrap());
+        assert_eq!(store.borrow::<u32>(), &42);
+        assert_eq!(store.type_names(), vec!["u32"]);
+        let mapped = store.map_any(TypeId::of::<u32>(), |b| {
+            Box::new(b.downcast::<u32>().unwrap().to_string())
+        });
+        assert!(mapped.unwrap());
+        assert!(!store.has::<u32>());
+        assert_eq!(store.borrow::<String>(), "42");
+        assert_eq!(store.len(), 1);
+
+        store.put(7u32);
+        store.freeze_type::<u8>();
+        let mapped = store.map_any(TypeId::of::<u32>(), |b| {
+            Box::new(*b.downcast::<u32>().unwrap() as u8)
+        });
+        assert_eq!(mapped.unwrap_err().downcast_ref::<u8>(), Some(&7));
+        assert!(!store.has::<u32>());
+        assert!(!store.has::<u8>());
+    }
+
+    #[test]
+    fn count_where() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store.put(AnotherStruct { value: "a" });
+        store.put(auth::Session);
+        let internal = [TypeId::of::<auth::Session>(), TypeId::of::<auth::User>()];
+        assert_eq!(store.count_where(|id| !internal.contains(&id)), 2);
+        assert_eq!(store.count_where(|_| true), store.len());
+        assert_eq!(store.count_where(|_| false), 0);
+    }
+
+    #[test]
+    fn borrow_cow() {
+        use std::borrow::Cow;
+
+        let mut store = GothamStore::default();
+        let value = store.borrow_cow(|| "fallback".to_string());
+        assert!(matches!(value, Cow::Owned(ref s) if s == "fallback"));
+        assert!(!store.has::<String>());
+        store.put("value".to_string());
+        let value = store.borrow_cow::<String>(|| unreachable!());
+        assert!(matches!(value, Cow::Borrowed(s) if s == "value"));
+    }
+
+    #[cfg(feature = "timestamps")]
+    #[test]
+    fn evict_older_than_duration() {
+        use std::time::{Duration, Instant};
+
+        let mut store = GothamStore::default();
+        assert!(store.insert_instant::<MyStruct>().is_none());
+        let before = Instant::now();
+        store.put(MyStruct { value: 100 });
+        assert!(store.insert_instant::<MyStruct>().unwrap() >= before);
+
+        std::thread::sleep(Duration::from_millis(50));
+        store.put(AnotherStruct { value: "a" });
+        assert_eq!(
+            store.evict_older_than_duration(Duration::from_millis(25)),
+            1
+        );
+        assert!(!store.has::<MyStruct>());
+        assert!(store.has::<AnotherStruct>());
+    }
+
+    #[test]
+    fn put_with_ttl() {
+        use std::time::Duration;
+
+        let (long, short) = (Duration::from_secs(600), Duration::from_millis(1));
+        let mut store = GothamStore::default();
+        store.put_with_ttl(MyStruct { value: 100 }, long);
+        assert_eq!(store.try_borrow::<MyStruct>().unwrap().value, 100);
+
+        store.put_with_ttl(MyStruct { value: 100 }, short);
+        store.put(AnotherStruct { value: "a" });
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(!store.has::<MyStruct>());
+        assert!(store.try_borrow::<MyStruct>().is_none());
+        assert_eq!(store.len(), 2);
+        assert!(store.try_take::<MyStruct>().is_none());
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.borrow::<AnotherStruct>().value, "a");
+
+        store.put_with_ttl(MyStruct { value: 1 }, short);
+        store.put(MyStruct { value: 2 });
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(store.borrow::<MyStruct>().value, 2);
+
+        store.put_with_ttl(MyStruct { value: 3 }, short);
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(store.swap_value(MyStruct { value: 4 }).is_none());
+        assert_eq!(store.borrow::<MyStruct>().value, 4);
+    }
+
+    #[test]
+    fn put_with_ttl_moves() {
+        use std::time::Duration;
+
+        let (long, short) = (Duration::from_secs(600), Duration::from_millis(1));
+        let id = TypeId::of::<MyStruct>();
+        let mut store = GothamStore::default();
+        let mut dest = GothamStore::default();
+        store.put_with_ttl(MyStruct { value: 1 }, long);
+        assert!(store.move_type_into::<MyStruct>(&mut dest));
+        assert!(dest.meta[&id].expires.is_some());
+        dest.move_all_into(&mut store);
+        assert!(store.meta[&id].expires.is_some());
+        drop(store.take_guard::<MyStruct>());
+        assert!(store.meta[&id].expires.is_some());
+
+        store.put_with_ttl(MyStruct { value: 2 }, short);
+        store.put(AnotherStruct { value: "a" });
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(store.take_guard::<MyStruct>().is_none());
+        store.put_with_ttl(MyStruct { value: 3 }, short);
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(matches!(
+            store.borrow_pair_mut_checked::<MyStruct, AnotherStruct>(),
+            Err(StoreError::Missing("gotham_store::tests::MyStruct"))
+        ));
+        store.put_with_ttl(MyStruct { value: 4 }, short);
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(!store.move_type_into::<MyStruct>(&mut dest));
+        store.put_with_ttl(MyStruct { value: 5 }, short);
+        std::thread::sleep(Duration::from_millis(10));
+        store.move_all_into(&mut dest);
+        assert!(!dest.has::<MyStruct>());
+        assert_eq!(dest.len(), 1);
+        store.put_with_ttl(MyStruct { value: 6 }, short);
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(store.drain_named().is_empty());
+    }
+
+    #[test]
+    fn take_with_empty_flag() {
+        let mut store = GothamStore::default();
+        assert!(store.take_with_empty_flag::<MyStruct>().is_none());
+        store.put(MyStruct { value: 100 });
+        store.put(AnotherStruct { value: "a" });
+        let (my_struct, empty) = store.take_with_empty_flag::<MyStruct>().unwrap();
+        assert_eq!((my_struct.value, empty), (100, false));
+        let (another, empty) = store.take_with_empty_flag::<AnotherStruct>().unwrap();
+        assert_eq!((another.value, empty), ("a", true));
+    }
+
+    #[test]
+    fn from_providers() {
+        let providers: Vec<Provider> = vec![
+            Box::new(|store| store.put(MyStruct { value: 100 })),
+            Box::new(|store| {
+                store.put(AnotherStruct { value: "a" });
+                store.put(auth::Session);
+            }),
+            Box::new(|store| store.borrow_mut::<MyStruct>().value += 1),
+        ];
+        let store = GothamStore::from_providers(providers);
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.borrow::<MyStruct>().value, 101);
+        assert_eq!(store.borrow::<AnotherStruct>().value, "a");
+        assert!(store.has::<auth::Session>());
+    }
+
+    #[test]
+    fn swap_value() {
+        let mut store = GothamStore::default();
+        assert!(store.swap_value(MyStruct { value: 1 }).is_none());
+        let before = store.borrow::<MyStruct>() as *const MyStruct;
+        let old = store.swap_value(MyStruct { value: 2 }).unwrap();
+        assert_eq!(old.value, 1);
+        assert_eq!(store.borrow::<MyStruct>().value, 2);
+        assert_eq!(store.borrow::<MyStruct>() as *const MyStruct, before);
+    }
+
+    #[test]
+    fn into_parts() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store.keyed_entry::<u32>("a");
+        store.keyed_get_or_insert_with("b", || MyStruct { value: 1 });
+        let (store, keyed) = store.into_parts();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.borrow::<MyStruct>().value, 100);
+        assert!(store.keyed().is_empty());
+        assert_eq!(keyed.len(), 2);
+        assert_eq!(keyed.get_keyed::<u32>("a"), Some(&0));
+        assert_eq!(keyed.get_keyed::<MyStruct>("b").unwrap().value, 1);
+    }
+
+    #[test]
+    fn flatten_keyed() {
+        let mut keyed = KeyedStore::default();
+        keyed.put_keyed("a", MyStruct { value: 100 });
+        keyed.put_keyed("b", 1u32);
+        let store = GothamStore::flatten_keyed(keyed).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.borrow::<MyStruct>().value, 100);
+        assert_eq!(store.borrow::<u32>(), &1);
+
+        let mut keyed = KeyedStore::default();
+        keyed.put_keyed("a", MyStruct { value: 100 });
+        keyed.put_keyed("b", MyStruct { value: 200 });
+        keyed.put_keyed("c", 1u32);
+        let keyed = GothamStore::flatten_keyed(keyed).unwrap_err();
+        assert_eq!(keyed.len(), 3);
+        assert_eq!(keyed.get_keyed::<MyStruct>("b").unwrap().value, 200);
+    }
+
+    #[test]
+    fn borrow_lens() {
+        let mut store = GothamStore::default();
+        store.register_lens("my_value", |s: &MyStruct| &s.value);
+        assert!(store.borrow_lens::<i32>("my_value").is_none());
+        store.put(MyStruct { value: 100 });
+        assert_eq!(store.borrow_lens::<i32>("my_value"), Some(&100));
+        assert!(store.borrow_lens::<u32>("my_value").is_none());
+        assert!(store.borrow_lens::<i32>("missing").is_none());
+    }
+
+    #[cfg(feature = "arc-swap")]
+    #[test]
+    fn atomic_gotham_store() {
+        use super::{AtomicGothamStore, SyncGothamStore};
+        use std::sync::Arc;
+
+        fn snapshot(version: u32) -> SyncGothamStore {
+            let mut store = SyncGothamStore::default();
+            store.put(version);
+            store.put(version.to_string());
+            store
+        }
+
+        let atomic = Arc::new(AtomicGothamStore::new(snapshot(0)));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let atomic = Arc::clone(&atomic);
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let store = atomic.load();
+                        assert_eq!(store.borrow::<u32>().to_string(), *store.borrow::<String>());
+                    }
+                })
+            })
+            .collect();
+        for version in 1..=100 {
+            atomic.store(snapshot(version));
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(atomic.load().borrow::<u32>(), &100);
+    }
+
+    #[test]
+    fn defragment() {
+        let mut store = GothamStore::default();
+        for i in 0..16u8 {
+            store.put(i);
+            store.put(i as u16);
+        }
+        store.take::<u8>();
+        store.put(MyStruct { value: 100 });
+        store.put(AnotherStruct { value: "a" });
+        let ids = store.sorted_type_ids();
+        let before = store.borrow::<MyStruct>() as *const MyStruct;
+        store.defragment();
+        assert_eq!(store.sorted_type_ids(), ids);
+        assert_eq!(store.borrow::<MyStruct>() as *const MyStruct, before);
+        assert_eq!(store.borrow::<MyStruct>().value, 100);
+        assert_eq!(store.borrow::<AnotherStruct>().value, "a");
+        assert_eq!(store.borrow::<u16>(), &15);
+    }
+
+    #[test]
+    fn store_chain() {
+        let mut request = GothamStore::default();
+        let mut session = GothamStore::default();
+        let mut global = GothamStore::default();
+        request.put(auth::Session);
+        session.put(MyStruct { value: 2 });
+        global.put(MyStruct { value: 3 });
+        global.put(AnotherStruct { value: "global" });
+
+        let stores = chain(&[&request, &session, &global]);
+        assert!(stores.has::<auth::Session>());
+        assert_eq!(stores.try_borrow::<MyStruct>().unwrap().value, 2);
+        assert_eq!(
+            stores.try_borrow::<AnotherStruct>().unwrap().value,
+            "global"
+        );
+        assert!(stores.try_borrow::<auth::User>().is_none());
+        assert!(!TypeStore::has::<auth::User>(&stores));
+    }
+
+    #[test]
+    fn drain_named() {
+        let mut store = GothamStore::default();
+        store.put(MyStruct { value: 100 });
+        store.insert_any(TypeId::of::<u8>(), Box::new(1u8));
+        let mut drained = store.drain_named();
+        assert!(store.is_empty());
+        drained.sort_by_key(|(name, _)| *name);
+        assert_eq!(drained.len(), 2);
+        assert_eq!(drained[0].0, "<unnamed>");
+        assert_eq!(drained[0].1.downcast_ref::<u8>(), Some(&1));
+        assert_eq!(drained[1].0, "gotham_store::tests::MyStruct");
+        assert_eq!(drained[1].1.downcast_ref::<MyStruct>().unwrap().value, 100);
+    }
+
+    #[test]
+    fn put_indexed() {
+        let mut store = GothamStore::default();
+        store.put_indexed(0, MyStruct { value: 0 });
+        store.put_indexed(1, MyStruct { value: 1 });
+        store.put_indexed(5, MyStruct { value: 5 });
+        store.put_indexed(5, MyStruct { value: 50 });
+        store.put_indexed(0, 1u8);
+        store.keyed_get_or_insert_with("0", || MyStruct { value: -1 });
+        assert_eq!(store.get_indexed::<MyStruct>(1).unwrap().value, 1);
+        assert_eq!(store.get_indexed::<MyStruct>(5).unwrap().value, 50);
+        assert!(store.get_indexed::<MyStruct>(2).is_none());
+        assert_eq!(store.get_indexed::<u8>(0), Some(&1));
+        assert_eq!(store.len_indexed::<MyStruct>(), 3);
+        assert_eq!(store.len_indexed::<u8>(), 1);
+        assert_eq!(store.keyed().len(), 5);
+    }
+
+    #[test]
+    fn borrow_nested() {
+        struct Module {
+            context: GothamStore,
+        }
+
+        let mut context = GothamStore::default();
+        context.put(MyStruct { value: 100 });
+        let mut store = GothamStore::default();
+        assert!(store
+            .borrow_nested::<Module, MyStruct>(|m| &m.context)
+            .is_none());
+        store.put(Module { context });
+        let value = store.borrow_nested::<Module, MyStruct>(|m| &m.context);
+        assert_eq!(value.unwrap().value, 100);
+        assert!(store
+            .borrow_nested::<Module, AnotherStruct>(|m| &m.context)
+            .is_none());
+    }
+
+    #[test]
+    fn resolve_after_panic() {
+        use std::cell::Cell;
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        thread_local!(static FAIL: Cell<bool> = const { Cell::new(true) });
+
+        struct X;
+        struct Y;
+
+        impl Provide for X {
+            type Deps = (Y,);
+            fn provide(_: &GothamStore) -> Self {
+                X
+            }
+        }
+
+        impl Provide for Y {
+            type Deps = ();
+            fn provide(_: &GothamStore) -> Self {
+                if FAIL.with(|fail| fail.replace(false)) {
+                    panic!("provider failed");
+                }
+                Y
+            }
+        }
+
+        let mut store = GothamStore::default();
+        assert!(catch_unwind(AssertUnwindSafe(|| {
+            store.resolve::<X>();
+        }))
+        .is_err());
+        assert!(store.resolving.is_empty());
+        store.resolve::<X>();
+        assert!(store.has::<Y>());
     }
 }